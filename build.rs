@@ -1,4 +1,6 @@
 fn main() {
+    // cc only asks to be rerun on environment changes, the sources need to be listed too.
+    println!("cargo:rerun-if-changed=c_src/linux-comms.c");
     #[cfg(target_os = "linux")]
     cc::Build::new()
         .file("c_src/linux-comms.c")
@@ -4,15 +4,23 @@
  *
  * Reason: Because rust cannot handle /dev devices correctly.
  */
+#include <stddef.h>
 #include <stdint.h>
 #include <string.h>
 #include <unistd.h>
 
 #include <fcntl.h>
+#include <poll.h>
+
+// Maximum length of a message read from the host.
+#define COMMS_MAX_LEN 1024
 
 // Internal file descriptor to talk with host.
 static int fd = -1;
-static char buffer[1024]; // TODO: Make this unlimited size eventually.
+// One extra byte keeps the buffer NUL terminated when a message fills it.
+static char buffer[COMMS_MAX_LEN + 1]; // TODO: Make this unlimited size eventually.
+// Milliseconds to wait for the host in read_comms, negative values wait forever.
+static int timeout = -1;
 
 /*
  * init_comms - Initializes the communication layer to be used for host -> guest comms.
@@ -32,24 +40,64 @@ int32_t init_comms()
 }
 
 /*
- * read_comms - Reads an internal buffer of size 1024 for communication with the host.
- * @returns - Pointer to internal buffer.
+ * set_comms_timeout - Sets how long read_comms waits for a message from the host.
+ * @param ms - Milliseconds to wait, negative values wait forever.
+ */
+void set_comms_timeout(int32_t ms)
+{
+    timeout = ms;
+}
+
+/*
+ * comms_max_len - Gets the maximum length of a message returned by read_comms.
+ * @returns - Maximum length in bytes, excluding the NUL terminator.
+ */
+size_t comms_max_len()
+{
+    return COMMS_MAX_LEN;
+}
+
+/*
+ * read_comms - Reads an internal buffer of size COMMS_MAX_LEN for communication with the host.
+ * @returns - Pointer to internal buffer, or NULL if the communication channel is not
+ *     initialized or the host hung up. The buffer is static, it must NOT be freed by the caller.
  *
  * Side effects
  * - Uses a static buffer, meaning if someone were to hold onto this buffer after the fact
  *     they would be able to alter/spy on long lasting communications. Ensure all access to
  *     this function ONLY occurs under the read_string function, and use that one.
  * - NOTE: NOT THREAD SAFE EITHER.
- * - NOTE: If communication channel is not initialized, it will return the empty string.
+ * - NOTE: If the host sends nothing within the timeout, it will return the empty string.
  */
 const char *read_comms()
 {
+    struct pollfd pfd = { .fd = fd, .events = POLLIN };
+
     if (fd == -1) return NULL;
-    memset(buffer, 0, 1024 * sizeof(char));
-    read(fd, buffer, 1024 * sizeof(char));
+    memset(buffer, 0, sizeof(buffer));
+    if (poll(&pfd, 1, timeout) > 0) {
+        // Without the host on the other end, reads return at once with nothing.
+        if (!(pfd.revents & POLLIN))
+            return NULL;
+        if (read(fd, buffer, COMMS_MAX_LEN * sizeof(char)) <= 0)
+            return NULL;
+    }
     return buffer;
 }
 
+/*
+ * close_comms - Closes the communication layer, so init_comms opens it again.
+ *
+ * Side effects
+ * - Closes the long lasting file descriptor opened by init_comms.
+ */
+void close_comms()
+{
+    if (fd != -1)
+        close(fd);
+    fd = -1;
+}
+
 /*
  * write_command - Writes a command into the host -> guest communication chardev.
  * @param str - String to write into host -> guest buffer.
@@ -2,17 +2,40 @@
 // SPDX-License-Identifier: GPL-2.0
 #include <stdio.h>
 
+typedef void (*log_callback_t)(const void *ctx, const char *msg);
+
+static log_callback_t log_callback;
+static const void *log_ctx;
+
+// Logs through the guest program once it handed over its callback, stdout otherwise.
+static void plugin_log(const char *msg) {
+    if (log_callback) {
+        log_callback(log_ctx, msg);
+    } else {
+        printf("%s\n", msg);
+    }
+}
+
+void set_log_callback(log_callback_t callback, const void *ctx) {
+    log_callback = callback;
+    log_ctx = ctx;
+}
+
 char* start() {
-    printf("Starting test plugin\n");
+    plugin_log("Starting test plugin");
     return NULL;
 }
 
+// Responses are copied by the guest program and never freed, and have to be JSON objects.
 char* cmd_process(const char *cmd_process) {
-    printf("Processing %s\n", cmd_process);
-    return "Processed";
+    char line[256];
+
+    snprintf(line, sizeof(line), "Processing %s", cmd_process);
+    plugin_log(line);
+    return "{\"status\":\"processed\"}";
 }
 
 char* stop() {
-    printf("Stop test plugin\n");
+    plugin_log("Stop test plugin");
     return NULL;
 }
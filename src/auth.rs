@@ -0,0 +1,108 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This handles authentication of the messages exchanged with the host.
+//!
+//! When a pre-shared key is configured, every message on the wire is wrapped inside an
+//! [AuthEnvelope] carrying a hex encoded HMAC-SHA256 of the message, computed with the key.
+//! Messages from the host with a missing or wrong HMAC are rejected.
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::result::Result;
+
+use crate::common::GVMError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Authenticated framing of a message.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthEnvelope {
+    /// Hex encoded HMAC-SHA256 of `msg`.
+    pub hmac: String,
+    /// The message being authenticated.
+    pub msg: String,
+}
+
+/// Wraps `msg` inside an [AuthEnvelope] signed with `key`.
+pub fn seal(key: &[u8], msg: String) -> Result<String, GVMError> {
+    let mut mac = HmacSha256::new_from_slice(key).map_err(|_| GVMError::InvalidConfig)?;
+    mac.update(msg.as_bytes());
+
+    let envelope = AuthEnvelope {
+        hmac: hex::encode(mac.finalize().into_bytes()),
+        msg,
+    };
+    serde_json::to_string(&envelope).map_err(|_| GVMError::InvalidMessage)
+}
+
+/// Unwraps the [AuthEnvelope] in `s`, returning its message if the HMAC matches `key`.
+pub fn open(key: &[u8], s: &str) -> Result<String, GVMError> {
+    let envelope: AuthEnvelope = serde_json::from_str(s).map_err(|_| GVMError::AuthFailed)?;
+    let expected = hex::decode(&envelope.hmac).map_err(|_| GVMError::AuthFailed)?;
+
+    let mut mac = HmacSha256::new_from_slice(key).map_err(|_| GVMError::InvalidConfig)?;
+    mac.update(envelope.msg.as_bytes());
+    mac.verify_slice(&expected)
+        .map_err(|_| GVMError::AuthFailed)?;
+
+    Ok(envelope.msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"pre-shared key";
+
+    #[test]
+    fn seal_open_round_trip() {
+        let sealed = seal(KEY, r#"{"type":"Network","payload":[]}"#.to_owned()).unwrap();
+        assert_eq!(
+            open(KEY, &sealed).unwrap(),
+            r#"{"type":"Network","payload":[]}"#
+        );
+    }
+
+    #[test]
+    fn wrong_key_rejected() {
+        let sealed = seal(KEY, "{}".to_owned()).unwrap();
+        assert!(matches!(
+            open(b"other key", &sealed),
+            Err(GVMError::AuthFailed)
+        ));
+    }
+
+    #[test]
+    fn tampered_hmac_rejected() {
+        let mut envelope: AuthEnvelope =
+            serde_json::from_str(&seal(KEY, "{}".to_owned()).unwrap()).unwrap();
+        let last = if envelope.hmac.ends_with('0') {
+            "1"
+        } else {
+            "0"
+        };
+        envelope.hmac.replace_range(envelope.hmac.len() - 1.., last);
+
+        let tampered = serde_json::to_string(&envelope).unwrap();
+        assert!(matches!(open(KEY, &tampered), Err(GVMError::AuthFailed)));
+    }
+
+    #[test]
+    fn tampered_message_rejected() {
+        let mut envelope: AuthEnvelope =
+            serde_json::from_str(&seal(KEY, "{}".to_owned()).unwrap()).unwrap();
+        envelope.msg = r#"{"type":"Command"}"#.to_owned();
+
+        let tampered = serde_json::to_string(&envelope).unwrap();
+        assert!(matches!(open(KEY, &tampered), Err(GVMError::AuthFailed)));
+    }
+
+    #[test]
+    fn unauthenticated_message_rejected() {
+        assert!(matches!(open(KEY, "{}"), Err(GVMError::AuthFailed)));
+        assert!(matches!(
+            open(KEY, r#"{"hmac":"not hex","msg":"{}"}"#),
+            Err(GVMError::AuthFailed)
+        ));
+    }
+}
@@ -0,0 +1,159 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This parses the command line of the GVM guest program.
+//!
+//! Every argument is optional, without any of them the guest program loads [CONFIG_PATH]
+//! and applies its changes to the system like it always did, so existing init scripts keep
+//! working.
+use log::LevelFilter;
+use std::path::PathBuf;
+use std::result::Result;
+
+use crate::config::CONFIG_PATH;
+
+/// Usage printed along invalid arguments.
+pub const USAGE: &str = "Usage: gvm-guest [--config <path>] [--apply-file <path>] [--dry-run] \
+                         [--log-level <level>] [--version]";
+
+/// Arguments of the GVM guest program.
+#[derive(Debug)]
+pub struct Args {
+    /// Configuration file to load.
+    pub config: PathBuf,
+    /// JSON list of networks to configure before exiting, instead of running the agent.
+    pub apply_file: Option<PathBuf>,
+    /// Logs the changes to the system instead of applying them.
+    pub dry_run: bool,
+    /// Minimum level of the logged records, ie. off, error, warn, info, debug or trace. Takes
+    /// precedence over the configured level.
+    pub log_level: Option<LevelFilter>,
+    /// Prints the version and exits.
+    pub version: bool,
+}
+
+/// Provides the value of `flag`, either given inline as `--flag=value` or as the next
+/// argument in `args`.
+fn value(
+    flag: &str,
+    inline: Option<&str>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<String, String> {
+    match inline {
+        Some(value) => Ok(value.to_owned()),
+        None => args
+            .next()
+            .ok_or_else(|| flag.to_owned() + " requires a value"),
+    }
+}
+
+impl Args {
+    /// Parses the arguments `args`, without the program name. Returns the reason the first
+    /// invalid argument was rejected.
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut parsed = Args {
+            config: PathBuf::from(CONFIG_PATH),
+            apply_file: None,
+            dry_run: false,
+            log_level: None,
+            version: false,
+        };
+
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, inline)) => (flag, Some(inline)),
+                None => (arg.as_str(), None),
+            };
+
+            match flag {
+                "--config" => parsed.config = PathBuf::from(value(flag, inline, &mut args)?),
+                "--apply-file" => {
+                    parsed.apply_file = Some(PathBuf::from(value(flag, inline, &mut args)?))
+                }
+                "--log-level" => {
+                    let level = value(flag, inline, &mut args)?;
+                    parsed.log_level = Some(
+                        level
+                            .parse()
+                            .map_err(|_| "Invalid log level ".to_owned() + &level)?,
+                    );
+                }
+                "--dry-run" if inline.is_none() => parsed.dry_run = true,
+                "--version" if inline.is_none() => parsed.version = true,
+                _ => return Err("Unknown argument ".to_owned() + &arg),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn no_arguments_keep_defaults() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.config, PathBuf::from(CONFIG_PATH));
+        assert_eq!(args.apply_file, None);
+        assert!(!args.dry_run);
+        assert_eq!(args.log_level, None);
+        assert!(!args.version);
+    }
+
+    #[test]
+    fn flags_with_separate_and_inline_values() {
+        let args = parse(&[
+            "--config",
+            "/tmp/guest.toml",
+            "--apply-file=/tmp/nets.json",
+            "--dry-run",
+            "--log-level=debug",
+            "--version",
+        ])
+        .unwrap();
+        assert_eq!(args.config, PathBuf::from("/tmp/guest.toml"));
+        assert_eq!(args.apply_file, Some(PathBuf::from("/tmp/nets.json")));
+        assert!(args.dry_run);
+        assert_eq!(args.log_level, Some(LevelFilter::Debug));
+        assert!(args.version);
+    }
+
+    #[test]
+    fn unknown_flags_rejected() {
+        assert_eq!(
+            parse(&["--verbose"]).unwrap_err(),
+            "Unknown argument --verbose"
+        );
+        assert_eq!(parse(&["config"]).unwrap_err(), "Unknown argument config");
+        assert_eq!(
+            parse(&["--dry-run=yes"]).unwrap_err(),
+            "Unknown argument --dry-run=yes"
+        );
+    }
+
+    #[test]
+    fn missing_value_rejected() {
+        assert_eq!(
+            parse(&["--config"]).unwrap_err(),
+            "--config requires a value"
+        );
+        assert_eq!(
+            parse(&["--dry-run", "--apply-file"]).unwrap_err(),
+            "--apply-file requires a value"
+        );
+    }
+
+    #[test]
+    fn invalid_log_level_rejected() {
+        assert_eq!(
+            parse(&["--log-level", "loud"]).unwrap_err(),
+            "Invalid log level loud"
+        );
+        assert_eq!(parse(&["--log-level="]).unwrap_err(), "Invalid log level ");
+    }
+}
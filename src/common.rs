@@ -2,35 +2,244 @@
 // SPDX-License-Identifier: GPL-2.0
 //! This is the commonly used types inside the GVM guest program.
 //!
-//! The host can only send 2 types of messages into the GVM guest program,
-//! the first is a [Network] vector, and the second is a [PluginMsg].
-use serde::{Deserialize, Serialize};
+//! Every message on the wire is tagged with its type. The host sends [HostToGuest] messages,
+//! carrying either the [Network] vector or a [PluginMsg], and the guest answers with
+//! [GuestToHost] messages carrying a [Command].
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::ops::Deref;
+use std::str::FromStr;
 
 /// GVM specific errors that can be run into in the program.
-#[derive(Debug)]
+///
+/// Errors are serialized for the host as `{"error": "<variant>", "context": <context>}`,
+/// where `context` is only present for variants carrying one.
+#[derive(Serialize, Debug)]
+#[serde(tag = "error", content = "context")]
 pub enum GVMError {
     /// IO Error related to file/host device control.
     IOError,
-    /// NIC was requested by the host but not found in the guest.
-    NicNotFound,
+    /// The communication line with the host is not open.
+    CommsClosed,
+    /// NIC with the given MAC address was requested by the host but not found in the guest.
+    NicNotFound(String),
+    /// Several networking devices have the requested MAC or PCI address, carries their names.
+    AmbiguousMac(Vec<String>),
     /// The plugin is not found.
+    #[cfg_attr(not(feature = "plugins"), allow(dead_code))]
     PluginNotFound,
     /// Plugin was already loaded.
+    #[cfg_attr(not(feature = "plugins"), allow(dead_code))]
     PluginLoaded,
+    /// Plugin exists but could not be loaded, carries the dlopen error (wrong architecture,
+    /// missing symbol, bad permissions, ...).
+    #[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+    PluginLoadFailed(String),
+    /// The maximum number of loaded plugins was reached.
+    #[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+    PluginLimitReached,
+    /// Plugin answered a command with something else than a JSON object, carries the
+    /// reason.
+    #[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+    PluginBadResponse(String),
     /// Plugin command was not supported by GVM Guest.
     PluginCommandNotSupported,
+    /// Plugin is still starting in the background, so it can't take commands yet.
+    #[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+    PluginStarting,
+    /// Message could not be encoded for the host communication channel.
+    InvalidMessage,
+    /// Message from the host is longer than the limit of the guest, carries its length in
+    /// bytes. The length of a message truncated by the host communication device is only
+    /// known to reach the limit of the device.
+    MessageTooLarge(usize),
+    /// Message could not be serialized to JSON, carries the reason.
+    Serialization(String),
+    /// The guest configuration contains an invalid value.
+    InvalidConfig,
+    /// Multiple networking backends look active, so we can't tell which one to configure.
+    AmbiguousBackend,
+    /// The networking backend running on the system was left out of this build, carries the
+    /// backend.
+    UnsupportedBackend(String),
+    /// The address of a NIC is not an IPv4 address, or not an IP address followed by an
+    /// optional valid cidr. Carries the address.
+    InvalidAddress(String),
+    /// The MAC address is not six colon separated hex octets, carries the address.
+    InvalidMac(String),
+    /// The gateway is not an IP address, or in the legacy form of gateway-ip/cidr.
+    InvalidGateway(String),
+    /// The address has no valid prefix, nor a legacy gateway to take its cidr from. Carries
+    /// the address.
+    InvalidPrefix(String),
+    /// The routing policy has an invalid table, prefix or gateway, or no gateway to route
+    /// through. Carries the offending value.
+    #[cfg_attr(
+        not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+        allow(dead_code)
+    )]
+    InvalidRoutePolicy(String),
+    /// The link speed is 0, or the duplex mode isn't full or half. Carries the offending
+    /// value.
+    InvalidLinkSettings(String),
+    /// Message from the host failed authentication.
+    AuthFailed,
+    /// The bonding mode is not supported by the bonding driver.
+    InvalidBondMode(String),
+    /// The neighbor IP is not an IP address, or its MAC is not a MAC address. Carries the
+    /// offending value.
+    InvalidNeighbor(String),
+    /// The MACsec key or key name is not hex encoded, or doesn't have a valid length.
+    /// Carries the offending field, never the key.
+    InvalidMacsec(String),
+    /// The sysctl name is not a single per-interface setting, or its value holds something
+    /// else than letters, digits, `-` and `_`. Carries the offending value.
+    InvalidSysctl(String),
+    /// The VRF name is not a legal interface name, or its table is missing or reserved.
+    /// Carries the offending value.
+    InvalidVrf(String),
+    /// The WireGuard tunnel name is not a legal interface name, a key is not a base64
+    /// WireGuard key, the endpoint is not host:port, or an allowed IP is not in cidr
+    /// notation. Carries the offending value.
+    InvalidWireGuard(String),
+    /// The hostname is not a legal hostname.
+    InvalidHostname(String),
+    /// The timezone is not in the zoneinfo database, or the NTP server is neither a hostname
+    /// nor an IP address. Carries the offending value.
+    InvalidTime(String),
+    /// The environment variable name is not a legal name.
+    #[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+    InvalidEnvName(String),
+    /// The nameserver is not an IP address, or the search domain is not a legal domain.
+    InvalidDns(String),
+    /// The interface did not come up after applying the network configuration.
+    NetworkNotUp(String),
+    /// The interface carries the default route of the guest, and is only brought down when
+    /// forced. Carries the interface.
+    LinkInUse(String),
+    /// The interface did not reach the requested state, carries the interface.
+    LinkStateUnchanged(String),
+    /// Message from the host is valid JSON, but not the shape of any known message. Carries
+    /// the reason it was rejected.
+    MalformedMessage(String),
+    /// The sequence number of a command is a duplicate or skips ahead, carries the sequence
+    /// number expected instead.
+    UnexpectedSequence(u64),
+    /// The file of networks to apply is not a JSON list of networks, carries the reason.
+    InvalidNetworkFile(String),
+    /// The log is not inside the allowlist of logs the host can stream, carries the log.
+    LogNotAllowed(String),
+    /// The log is already streamed to the host, carries the log.
+    StreamActive(String),
+    /// The plugin name is not a plain file name, carries the name.
+    #[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+    InvalidPluginName(String),
+    /// The plugin doesn't match the checksum sent by the host, or lacks a valid signature
+    /// from the configured public key, so it is neither installed nor loaded. Carries the
+    /// reason.
+    #[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+    PluginIntegrity(String),
+    /// The guest program is paused, so the command was not executed.
+    AgentPaused,
 }
 
 impl fmt::Display for GVMError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             GVMError::IOError => write!(f, "IOError"),
-            GVMError::NicNotFound => write!(f, "NicNotFound"),
+            GVMError::CommsClosed => write!(f, "CommsClosed"),
+            GVMError::NicNotFound(_) => write!(f, "NicNotFound"),
+            GVMError::AmbiguousMac(_) => write!(f, "AmbiguousMac"),
             GVMError::PluginNotFound => write!(f, "PluginNotFound"),
             GVMError::PluginLoaded => write!(f, "PluginLoaded"),
+            GVMError::PluginLoadFailed(err) => write!(f, "PluginLoadFailed: {}", err),
+            GVMError::PluginLimitReached => write!(f, "PluginLimitReached"),
+            GVMError::PluginBadResponse(_) => write!(f, "PluginBadResponse"),
             GVMError::PluginCommandNotSupported => write!(f, "PluginCommandNotSupported"),
+            GVMError::PluginStarting => write!(f, "PluginStarting"),
+            GVMError::InvalidMessage => write!(f, "InvalidMessage"),
+            GVMError::MessageTooLarge(_) => write!(f, "MessageTooLarge"),
+            GVMError::Serialization(_) => write!(f, "Serialization"),
+            GVMError::InvalidConfig => write!(f, "InvalidConfig"),
+            GVMError::AmbiguousBackend => write!(f, "AmbiguousBackend"),
+            GVMError::UnsupportedBackend(_) => write!(f, "UnsupportedBackend"),
+            GVMError::InvalidAddress(_) => write!(f, "InvalidAddress"),
+            GVMError::InvalidMac(_) => write!(f, "InvalidMac"),
+            GVMError::InvalidGateway(_) => write!(f, "InvalidGateway"),
+            GVMError::InvalidPrefix(_) => write!(f, "InvalidPrefix"),
+            GVMError::InvalidRoutePolicy(_) => write!(f, "InvalidRoutePolicy"),
+            GVMError::InvalidLinkSettings(_) => write!(f, "InvalidLinkSettings"),
+            GVMError::AuthFailed => write!(f, "AuthFailed"),
+            GVMError::InvalidBondMode(_) => write!(f, "InvalidBondMode"),
+            GVMError::InvalidNeighbor(_) => write!(f, "InvalidNeighbor"),
+            GVMError::InvalidMacsec(_) => write!(f, "InvalidMacsec"),
+            GVMError::InvalidSysctl(_) => write!(f, "InvalidSysctl"),
+            GVMError::InvalidVrf(_) => write!(f, "InvalidVrf"),
+            GVMError::InvalidWireGuard(_) => write!(f, "InvalidWireGuard"),
+            GVMError::InvalidHostname(_) => write!(f, "InvalidHostname"),
+            GVMError::InvalidTime(_) => write!(f, "InvalidTime"),
+            GVMError::InvalidEnvName(_) => write!(f, "InvalidEnvName"),
+            GVMError::InvalidDns(_) => write!(f, "InvalidDns"),
+            GVMError::NetworkNotUp(_) => write!(f, "NetworkNotUp"),
+            GVMError::LinkInUse(_) => write!(f, "LinkInUse"),
+            GVMError::LinkStateUnchanged(_) => write!(f, "LinkStateUnchanged"),
+            GVMError::MalformedMessage(_) => write!(f, "MalformedMessage"),
+            GVMError::UnexpectedSequence(_) => write!(f, "UnexpectedSequence"),
+            GVMError::InvalidNetworkFile(_) => write!(f, "InvalidNetworkFile"),
+            GVMError::LogNotAllowed(_) => write!(f, "LogNotAllowed"),
+            GVMError::StreamActive(_) => write!(f, "StreamActive"),
+            GVMError::InvalidPluginName(_) => write!(f, "InvalidPluginName"),
+            GVMError::PluginIntegrity(_) => write!(f, "PluginIntegrity"),
+            GVMError::AgentPaused => write!(f, "AgentPaused"),
+        }
+    }
+}
+
+impl GVMError {
+    /// Serializes the error into a machine readable JSON object for the host.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| self.to_string())
+    }
+
+    /// Provides the exit code of the guest program when stopped by the error, so supervisors
+    /// can tell apart failures worth retrying from those needing a fix:
+    ///
+    /// - 2: invalid command line arguments, reported before any error can occur
+    /// - 3: invalid configuration file
+    /// - 4: communication failure with the host
+    /// - 5: network configuration failure
+    /// - 1: any other failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GVMError::InvalidConfig => 3,
+            GVMError::CommsClosed
+            | GVMError::AuthFailed
+            | GVMError::InvalidMessage
+            | GVMError::MessageTooLarge(_)
+            | GVMError::MalformedMessage(_) => 4,
+            GVMError::NicNotFound(_)
+            | GVMError::AmbiguousMac(_)
+            | GVMError::AmbiguousBackend
+            | GVMError::UnsupportedBackend(_)
+            | GVMError::InvalidAddress(_)
+            | GVMError::InvalidMac(_)
+            | GVMError::InvalidGateway(_)
+            | GVMError::InvalidPrefix(_)
+            | GVMError::InvalidRoutePolicy(_)
+            | GVMError::InvalidBondMode(_)
+            | GVMError::InvalidVrf(_)
+            | GVMError::InvalidWireGuard(_)
+            | GVMError::InvalidNeighbor(_)
+            | GVMError::InvalidMacsec(_)
+            | GVMError::InvalidSysctl(_)
+            | GVMError::InvalidLinkSettings(_)
+            | GVMError::InvalidDns(_)
+            | GVMError::NetworkNotUp(_)
+            | GVMError::InvalidNetworkFile(_) => 5,
+            _ => 1,
         }
     }
 }
@@ -42,7 +251,7 @@ impl From<io::Error> for GVMError {
 }
 
 /// Possible commands available inside the GVM Guest.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub enum GVMCmd {
     /// Gets a list of networking macs/ips/and gateways.
     GetNetwork,
@@ -56,6 +265,67 @@ pub enum GVMCmd {
     StopPlugin,
     /// Shuts down the guest program, eventually this will also shut down the system.
     ShutdownGuest,
+    /// Lists the plugins discovered inside the guest plugin directory.
+    ListAvailablePlugins,
+    /// Heartbeat, the guest immediately answers with a monotonically increasing counter.
+    Ping,
+    /// Capabilities handshake, the host lists the compression algorithms it supports and the
+    /// guest answers with the one it picked, `null` when none. Once picked, long messages
+    /// sent to the host are compressed.
+    Negotiate,
+    /// Reports whether the plugin started in the background is still starting, ready or
+    /// failed to start.
+    GetPluginStartStatus,
+    /// Removes the configuration of the NIC with the given MAC address.
+    RemoveNetwork,
+    /// Removes every network configuration file written by the guest along with the marker
+    /// file, and re-applies networking. The guest asks for its networks again on its next
+    /// start.
+    ResetNetwork,
+    /// Reports the uptime, processed commands, loaded plugins, last error and network
+    /// initialization status of the guest program.
+    GetStatus,
+    /// Sets the hostname of the guest.
+    SetHostname,
+    /// Sets the timezone and the NTP servers of the guest.
+    SetTime,
+    /// Replaces the DNS configuration of the guest, without touching the addressing.
+    SetDns,
+    /// Validates the environment of the guest, reporting which checks passed.
+    SelfTest,
+    /// Reports the distribution, kernel, architecture and networking backend of the guest.
+    GetFacts,
+    /// Streams the new lines of an allowlisted log as progress commands, until stopped. The
+    /// final reply carries why the stream ended.
+    StreamLog,
+    /// Stops streaming a log, or every log when none is given.
+    StopStream,
+    /// Installs or replaces a plugin inside the plugin directory, sent as base64 chunks.
+    InstallPlugin,
+    /// Reports the path and contents of the configuration files written for the NIC with
+    /// the given MAC address, or for every NIC when none is given.
+    GetNetworkConfig,
+    /// Stops executing commands, until resumed. Ping, GetStatus and Resume keep being
+    /// answered, every other command is rejected.
+    Pause,
+    /// Executes commands again after a pause.
+    Resume,
+    /// Brings the NIC with the given MAC address up or down, without touching its
+    /// configuration.
+    SetLinkState,
+    /// Reports whether networking was already initialized, when and for which MAC
+    /// addresses, so the host can tell whether to send the networks again.
+    GetNetworkInitStatus,
+    /// Sent by the guest once the line to the host is open, before initializing networking,
+    /// the response describes the guest program and the guest. Hosts not expecting it can
+    /// ignore it, or turn it off through the announce configuration.
+    Hello,
+    /// Sent by the guest once networking has been initialized and applied, the response maps
+    /// every configured interface to its operational state.
+    NetworkReady,
+    /// Sent by the guest when a message from the host was rejected, the response carries the
+    /// error.
+    Rejected,
 }
 
 /// Command to be sent from guest to the host.
@@ -65,29 +335,977 @@ pub struct Command {
     pub cmd: GVMCmd,
     /// This is the response for the different commands sent in/out of the guest.
     pub resp: Option<String>,
+    /// Structured response, ie. the JSON object returned by a plugin, sent instead of `resp`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
     /// This should be None when we initiate the command from the guest, and a success
     /// or failure otherwise.
     pub finished: Option<bool>,
+    /// Correlation ID of the host request this command answers, echoed back as is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Position of this command in a response split across several commands, as it was too
+    /// long for a single message. Only the last part carries `finished`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u32>,
+    /// Sequence number of the host command this command acknowledges or answers, echoed
+    /// back as is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
 }
 
-/// Networking structure to add to the system.
+/// Every message the guest sends to the host, serialized as
+/// `{"type": "<variant>", "payload": <command>}`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", content = "payload")]
+pub enum GuestToHost {
+    /// Request initiated by the guest, ie. asking the host for its networks.
+    Request(Command),
+    /// Receipt of a sequenced command from the host, sent before processing it. The final
+    /// reply follows.
+    Ack(Command),
+    /// Intermediate progress of a command, the final reply follows.
+    Progress(Command),
+    /// Final reply to a message from the host.
+    Reply(Command),
+    /// Notification initiated by the guest, ie. networking being ready.
+    Event(Command),
+}
+
+impl GuestToHost {
+    /// Gets the command carried by the message.
+    pub fn command(&self) -> &Command {
+        match self {
+            GuestToHost::Request(cmd)
+            | GuestToHost::Ack(cmd)
+            | GuestToHost::Progress(cmd)
+            | GuestToHost::Reply(cmd)
+            | GuestToHost::Event(cmd) => cmd,
+        }
+    }
+}
+
+/// Every message the host sends to the guest, serialized as
+/// `{"type": "<variant>", "payload": <message>}`.
 #[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "payload")]
+pub enum HostToGuest {
+    /// Reply to [GVMCmd::GetNetwork].
+    Network(NetworkReply),
+    /// Command for the guest program or one of its plugins.
+    Command(Box<PluginMsg>),
+}
+
+impl HostToGuest {
+    /// Parses a message from the host. Messages without a type predate the tagged messages,
+    /// and are told apart by their shape, commands being the only ones carrying `cmd`.
+    pub fn from_value(mut value: serde_json::Value) -> Result<HostToGuest, serde_json::Error> {
+        if value.get("type").is_some_and(|kind| kind == "Network") {
+            NetworkReply::from_value(value["payload"].take()).map(HostToGuest::Network)
+        } else if value.get("type").is_some() {
+            serde_json::from_value(value)
+        } else if value.get("cmd").is_some() {
+            serde_json::from_value(value).map(HostToGuest::Command)
+        } else {
+            NetworkReply::from_value(value).map(HostToGuest::Network)
+        }
+    }
+}
+
+/// How a NIC or bond gets its address.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Addressing {
+    /// `ip` is assigned statically.
+    #[default]
+    Static,
+    /// DHCP is tried first, when no lease is obtained in time `ip` is assigned statically
+    /// instead, along with `gateway` and the routing policies.
+    DhcpFallback,
+}
+
+/// Networking structure to add to the system.
+#[derive(Deserialize, Debug, Clone)]
 pub struct Network {
-    /// MAC address of the NIC passed into the guest.
-    pub mac: String,
-    /// IP address to assign to the NIC.
-    pub ip: String,
-    /// Gateway in the form of gateway-ip/cidr
-    pub gateway: String,
+    /// MAC address of the NIC passed into the guest, unused for tunnels.
+    #[serde(default)]
+    pub mac: MacAddr,
+    /// Name of the NIC inside the guest.
+    pub iface: Option<String>,
+    /// PCI address of the NIC inside the guest, ie. 0000:00:03.0, the domain can be left
+    /// out. `mac`, `iface` and `pci` are tried in the configured order to find the NIC.
+    pub pci: Option<String>,
+    /// IP address to assign to the NIC, optionally with its cidr. When absent or empty, the
+    /// address of the NIC is left alone and only its default route is replaced with one
+    /// through `gateway`.
+    #[serde(default, deserialize_with = "optional_ip")]
+    pub ip: Option<IpCidr>,
+    /// IP of the gateway the default route goes through, absent for NICs on a flat layer 2
+    /// segment which get no default route. The legacy gateway-ip/cidr form is still accepted.
+    pub gateway: Option<IpCidr>,
+    /// CIDR of `ip`, only optional when `ip` carries its cidr, or `gateway` is still in the
+    /// legacy gateway-ip/cidr form.
+    pub prefix: Option<u32>,
+    /// When present, a WireGuard tunnel is created instead of configuring a NIC. The tunnel
+    /// gets `ip` with the cidr of `prefix` assigned, and routes the allowed IPs of the peer.
+    pub wireguard: Option<WireGuard>,
+    /// When present, the NICs with the member MAC addresses are bonded together, and the
+    /// bond gets `ip` and `gateway` assigned. `mac` is unused.
+    pub bond: Option<Bond>,
+    /// Source based routing of a NIC with addresses in several subnets, every policy sends
+    /// the traffic it matches through its own routing table.
+    #[serde(default)]
+    #[cfg_attr(
+        not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+        allow(dead_code)
+    )]
+    pub routing_policy: Vec<RoutePolicy>,
+    /// When true, `ip` is added as an alias of the loopback instead of configuring a NIC,
+    /// giving the guest a stable management address whichever NICs are present. `mac` is
+    /// unused, and `prefix` can only be the full length of the address.
+    #[serde(default)]
+    pub loopback: bool,
+    /// When false, no DNS configuration is written for the NIC, ie. a secondary data plane
+    /// NIC leaving the resolver configuration of the system untouched. Defaults to true.
+    pub configure_dns: Option<bool>,
+    /// Whether DNS answers resolved through the NIC or bond must pass DNSSEC validation.
+    /// When absent the system setting is left untouched.
+    pub dnssec: Option<bool>,
+    /// Whether DNS queries through the NIC or bond use DNS-over-TLS. When absent the system
+    /// setting is left untouched.
+    pub dns_over_tls: Option<bool>,
+    /// How the NIC or bond gets its address, unused for tunnels and loopback aliases.
+    #[serde(default)]
+    pub addressing: Addressing,
+    /// When false, the NIC or bond uses no RFC 4941 temporary IPv6 addresses, ie. to keep a
+    /// stable address for firewalling. When absent the system setting is left untouched.
+    #[cfg_attr(
+        not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+        allow(dead_code)
+    )]
+    pub ipv6_privacy: Option<bool>,
+    /// Speed, duplex and autonegotiation pinned on the NIC, or on the members of a bond.
+    /// When absent the link parameters are left untouched.
+    pub link: Option<LinkSettings>,
+    /// MACsec protecting the traffic of the NIC, set up once the NIC is up. A NIC failing to
+    /// set it up is reported without failing the other NICs.
+    pub macsec: Option<Macsec>,
+    /// Name of the VRF the NIC or bond is enslaved to, isolating its traffic inside the
+    /// routing table `vrf_table`. The VRF is created when missing, and the default route
+    /// through `gateway` lives in its table.
+    pub vrf: Option<String>,
+    /// Routing table of `vrf`, required along with it.
+    pub vrf_table: Option<u32>,
+    /// Static neighbor entries of the NIC or bond, for peers which don't answer ARP. Added
+    /// once the interface is up, an entry failing to be added doesn't fail the NIC.
+    #[serde(default)]
+    pub neighbors: Vec<Neighbor>,
+    /// Sysctls of the NIC or bond, keyed relative to `net.ipv4.conf.<iface>`, ie.
+    /// `rp_filter`. Set once the interface is up, a sysctl failing to be set doesn't fail
+    /// the NIC.
+    #[serde(default)]
+    pub sysctls: BTreeMap<String, String>,
+    /// Length of the transmit queue of the NIC or bond, in packets. Set once the interface
+    /// is up, when absent the queue length is left untouched.
+    pub txqueuelen: Option<u32>,
+    /// Metric of the default route through `gateway`, or of the routes learnt over DHCP, the
+    /// lowest metric wins on guests with several NICs holding a default route. When absent
+    /// the backend picks the metric.
+    #[cfg_attr(
+        not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+        allow(dead_code)
+    )]
+    pub metric: Option<u32>,
+    /// Offloads toggled on the NIC or bond once it is up, offloads left out are untouched.
+    /// An offload failing to be toggled, ie. unsupported by the driver, doesn't fail the
+    /// NIC.
+    #[serde(default)]
+    pub offloads: Offloads,
 }
 
-/// Control of GVM guest utility message.
+impl Network {
+    /// Checks if only the default route of an already addressed NIC is replaced.
+    pub fn gateway_only(&self) -> bool {
+        self.ip.is_none()
+            && self.gateway.is_some()
+            && self.wireguard.is_none()
+            && self.bond.is_none()
+            && !self.loopback
+    }
+
+    /// Provides the IP address to assign to the NIC, which only NICs changing their gateway
+    /// go without.
+    pub fn address(&self) -> Result<IpAddr, GVMError> {
+        self.ip
+            .map(|ip| ip.addr)
+            .ok_or_else(|| GVMError::InvalidAddress(String::new()))
+    }
+
+    /// Checks if the NIC sets its DNSSEC or DNS-over-TLS mode.
+    pub fn dns_security(&self) -> bool {
+        self.dnssec.is_some() || self.dns_over_tls.is_some()
+    }
+
+    /// Checks if the DNS servers are configured for the NIC.
+    pub fn configures_dns(&self) -> bool {
+        self.configure_dns.unwrap_or(true)
+    }
+
+    /// Provides the VRF of the NIC and its routing table. VRF names follow the interface
+    /// name rules, and the table can't be one of the tables reserved by the kernel, ie. main
+    /// or local.
+    pub fn vrf(&self) -> Result<Option<(&str, u32)>, GVMError> {
+        let name = match &self.vrf {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+
+        let valid_name = !name.is_empty()
+            && name.len() <= 15
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !valid_name {
+            return Err(GVMError::InvalidVrf(name.clone()));
+        }
+
+        match self.vrf_table {
+            Some(table) if table != 0 && !(253..=255).contains(&table) => Ok(Some((name, table))),
+            Some(table) => Err(GVMError::InvalidVrf(table.to_string())),
+            None => Err(GVMError::InvalidVrf(name.clone())),
+        }
+    }
+}
+
+/// MAC address, ie. 52:54:00:12:34:56, kept in lowercase like the addresses listed by the
+/// kernel. The empty address stands for no MAC at all, ie. for tunnels and bonds.
+#[derive(Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct MacAddr(String);
+
+impl FromStr for MacAddr {
+    type Err = GVMError;
+
+    fn from_str(s: &str) -> Result<MacAddr, GVMError> {
+        let octets: Vec<&str> = s.split(':').collect();
+        let valid = s.is_empty()
+            || (octets.len() == 6
+                && octets
+                    .iter()
+                    .all(|octet| octet.len() == 2 && u8::from_str_radix(octet, 16).is_ok()));
+        if !valid {
+            return Err(GVMError::InvalidMac(s.to_owned()));
+        }
+
+        Ok(MacAddr(s.to_lowercase()))
+    }
+}
+
+impl TryFrom<String> for MacAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<MacAddr, String> {
+        s.parse()
+            .map_err(|_| format!("invalid MAC address {:?}", s))
+    }
+}
+
+impl Deref for MacAddr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Logged in the same form as sent by the host.
+impl fmt::Debug for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+/// IP address optionally followed by a cidr, ie. 10.0.0.2 or 10.0.0.2/24.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct IpCidr {
+    /// The address itself.
+    pub addr: IpAddr,
+    /// Prefix length, between 1 and the length of the address.
+    pub cidr: Option<u32>,
+}
+
+impl FromStr for IpCidr {
+    type Err = GVMError;
+
+    fn from_str(s: &str) -> Result<IpCidr, GVMError> {
+        let invalid = || GVMError::InvalidAddress(s.to_owned());
+        let (addr, cidr) = match s.split_once('/') {
+            Some((addr, cidr)) => (addr, Some(cidr.parse::<u32>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if cidr.is_some_and(|cidr| cidr == 0 || cidr > max) {
+            return Err(invalid());
+        }
+
+        Ok(IpCidr { addr, cidr })
+    }
+}
+
+impl TryFrom<String> for IpCidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<IpCidr, String> {
+        s.parse().map_err(|_| format!("invalid IP address {:?}", s))
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.cidr {
+            Some(cidr) => write!(f, "{}/{}", self.addr, cidr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+// Logged in the same form as sent by the host.
+impl fmt::Debug for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+/// Deserializes an optional [IpCidr], where the empty string stands for an absent address.
+fn optional_ip<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<IpCidr>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(ip) if !ip.is_empty() => IpCidr::try_from(ip).map(Some).map_err(de::Error::custom),
+        _ => Ok(None),
+    }
+}
+
+/// Bonding modes supported by the linux bonding driver.
+pub const BOND_MODES: [&str; 7] = [
+    "balance-rr",
+    "active-backup",
+    "balance-xor",
+    "broadcast",
+    "802.3ad",
+    "balance-tlb",
+    "balance-alb",
+];
+
+/// Bond of several NICs passed into the guest.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Bond {
+    /// Name of the bond interface, ie. bond0.
+    pub name: String,
+    /// Bonding mode, one of [BOND_MODES], ie. 802.3ad for LACP or active-backup.
+    pub mode: String,
+    /// MAC addresses of the NICs to bond together.
+    pub members: Vec<MacAddr>,
+}
+
+impl Bond {
+    /// Validates the bonding mode against the modes known to the bonding driver.
+    pub fn validate(&self) -> Result<(), GVMError> {
+        if !BOND_MODES.contains(&self.mode.as_str()) {
+            return Err(GVMError::InvalidBondMode(self.mode.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Link parameters of a NIC, ie. a passthrough NIC on a fabric with a fixed speed.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LinkSettings {
+    /// Speed in Mbit/s, ie. 10000.
+    pub speed: Option<u32>,
+    /// Duplex mode, full or half.
+    pub duplex: Option<String>,
+    /// Whether the link autonegotiates its parameters.
+    #[cfg_attr(
+        not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+        allow(dead_code)
+    )]
+    pub autoneg: Option<bool>,
+}
+
+impl LinkSettings {
+    /// Validates the speed and duplex mode.
+    pub fn validate(&self) -> Result<(), GVMError> {
+        if self.speed == Some(0) {
+            return Err(GVMError::InvalidLinkSettings("0".to_owned()));
+        }
+        if let Some(duplex) = &self.duplex {
+            if duplex != "full" && duplex != "half" {
+                return Err(GVMError::InvalidLinkSettings(duplex.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Offloads of a NIC, `None` leaves the offload as it is.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Offloads {
+    /// Generic segmentation offload.
+    pub gso: Option<bool>,
+    /// Generic receive offload.
+    pub gro: Option<bool>,
+    /// TCP segmentation offload.
+    pub tso: Option<bool>,
+}
+
+impl Offloads {
+    /// Provides the offloads to toggle, by their ethtool name.
+    pub fn settings(&self) -> Vec<(&'static str, bool)> {
+        [("gso", self.gso), ("gro", self.gro), ("tso", self.tso)]
+            .into_iter()
+            .filter_map(|(name, enabled)| Some((name, enabled?)))
+            .collect()
+    }
+}
+
+/// MACsec of a NIC, keyed through MKA from a pre-shared connectivity association.
+#[derive(Deserialize, Clone)]
+pub struct Macsec {
+    /// Hex encoded connectivity association key, 16 or 32 bytes.
+    pub cak: String,
+    /// Hex encoded connectivity association key name, 1 to 32 bytes.
+    pub ckn: String,
+    /// Whether frames are encrypted, rather than only integrity protected. Defaults to true.
+    pub encrypt: Option<bool>,
+}
+
+impl Macsec {
+    /// Validates the encoding and length of the key and key name.
+    pub fn validate(&self) -> Result<(), GVMError> {
+        let hex = |value: &str| value.chars().all(|c| c.is_ascii_hexdigit());
+
+        if !hex(&self.cak) || ![32, 64].contains(&self.cak.len()) {
+            return Err(GVMError::InvalidMacsec("cak".to_owned()));
+        }
+        if !hex(&self.ckn)
+            || !(2..=64).contains(&self.ckn.len())
+            || !self.ckn.len().is_multiple_of(2)
+        {
+            return Err(GVMError::InvalidMacsec("ckn".to_owned()));
+        }
+
+        Ok(())
+    }
+
+    /// Checks if frames are encrypted.
+    pub fn encrypts(&self) -> bool {
+        self.encrypt.unwrap_or(true)
+    }
+}
+
+// The key is deliberately left out, so the configuration can be logged.
+impl fmt::Debug for Macsec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Macsec")
+            .field("ckn", &self.ckn)
+            .field("encrypt", &self.encrypt)
+            .finish()
+    }
+}
+
+/// Static neighbor entry, resolving the IP of a peer to its MAC address without ARP.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Neighbor {
+    /// IP address of the peer.
+    pub ip: IpAddr,
+    /// MAC address of the peer.
+    pub mac: MacAddr,
+}
+
+impl Neighbor {
+    /// Validates that the peer has a MAC address, the empty address being refused.
+    pub fn validate(&self) -> Result<(), GVMError> {
+        if self.mac.is_empty() {
+            return Err(GVMError::InvalidNeighbor(self.mac.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Routing policy of a NIC, the traffic matching `from` and `to` is looked up in `table`,
+/// which routes it through `via`.
+#[derive(Deserialize, Debug, Clone)]
+#[cfg_attr(
+    not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+    allow(dead_code)
+)]
+pub struct RoutePolicy {
+    /// Source prefix the policy matches, ie. the address of the NIC in the second subnet.
+    pub from: Option<IpCidr>,
+    /// Destination prefix the policy matches, also the destination routed by `table`. When
+    /// absent `table` holds a default route.
+    pub to: Option<IpCidr>,
+    /// Routing table of the policy, the reserved default, main and local tables are refused.
+    pub table: u32,
+    /// Gateway `table` routes through, when absent the gateway of the NIC is used.
+    pub via: Option<IpAddr>,
+}
+
+impl RoutePolicy {
+    /// Validates the table, and that the policy matches at least a prefix.
+    #[cfg_attr(
+        not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+        allow(dead_code)
+    )]
+    pub fn validate(&self) -> Result<(), GVMError> {
+        if self.table == 0 || self.table >= 253 {
+            return Err(GVMError::InvalidRoutePolicy(self.table.to_string()));
+        } else if self.from.is_none() && self.to.is_none() {
+            return Err(GVMError::InvalidRoutePolicy("from".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+/// WireGuard tunnel to provision inside the guest.
+#[derive(Deserialize, Clone)]
+pub struct WireGuard {
+    /// Name of the tunnel interface, ie. wg0.
+    pub name: String,
+    /// Base64 private key of the guest side of the tunnel.
+    pub private_key: String,
+    /// Port the guest listens on, when absent a random port is used.
+    pub listen_port: Option<u16>,
+    /// Base64 public key of the peer.
+    pub peer_public_key: String,
+    /// Endpoint of the peer in the form of host:port.
+    pub endpoint: Option<String>,
+    /// IPs, in cidr notation, routed through the tunnel.
+    pub allowed_ips: Vec<String>,
+}
+
+/// Checks `key` is a base64 encoded 32 byte WireGuard key.
+fn valid_wg_key(key: &str) -> bool {
+    key.len() == 44
+        && key.ends_with('=')
+        && key[..43]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+}
+
+impl WireGuard {
+    /// Validates every field written to the tunnel configuration, so none of them can break
+    /// out of its line or of the configuration directory.
+    pub fn validate(&self) -> Result<(), GVMError> {
+        let invalid = |value: &str| GVMError::InvalidWireGuard(value.to_owned());
+
+        let valid_name = !self.name.is_empty()
+            && self.name.len() <= 15
+            && self.name != "."
+            && self.name != ".."
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !valid_name {
+            return Err(invalid(&self.name));
+        }
+        // The private key itself is never reported.
+        if !valid_wg_key(&self.private_key) {
+            return Err(invalid("private_key"));
+        }
+        if !valid_wg_key(&self.peer_public_key) {
+            return Err(invalid(&self.peer_public_key));
+        }
+
+        if let Some(endpoint) = &self.endpoint {
+            let (host, port) = endpoint.rsplit_once(':').ok_or_else(|| invalid(endpoint))?;
+            let valid_host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+                Some(ip) => ip.parse::<Ipv6Addr>().is_ok(),
+                None => {
+                    !host.is_empty()
+                        && host
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                }
+            };
+            if !valid_host || !port.parse::<u16>().is_ok_and(|port| port != 0) {
+                return Err(invalid(endpoint));
+            }
+        }
+
+        for allowed in &self.allowed_ips {
+            let (addr, cidr) = allowed.split_once('/').ok_or_else(|| invalid(allowed))?;
+            let max = match addr.parse::<IpAddr>() {
+                Ok(IpAddr::V4(_)) => 32,
+                Ok(IpAddr::V6(_)) => 128,
+                Err(_) => return Err(invalid(allowed)),
+            };
+            if !cidr.parse::<u32>().is_ok_and(|cidr| cidr <= max) {
+                return Err(invalid(allowed));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The private key is deliberately left out, so tunnels can be logged.
+impl fmt::Debug for WireGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WireGuard")
+            .field("name", &self.name)
+            .field("listen_port", &self.listen_port)
+            .field("peer_public_key", &self.peer_public_key)
+            .field("endpoint", &self.endpoint)
+            .field("allowed_ips", &self.allowed_ips)
+            .finish()
+    }
+}
+
+/// Reply of the host to [GVMCmd::GetNetwork].
 #[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum NetworkReply {
+    /// Networks to add to the system, an empty list means the host isn't ready yet.
+    Nets(Vec<Network>),
+    /// Explicit signal from the host that the guest has no NICs to configure.
+    NoNics { no_nics: bool },
+}
+
+impl NetworkReply {
+    /// Parses a network reply, with a diagnostic pointing at the expected shapes, as the
+    /// untagged variants would otherwise fail without any detail.
+    pub fn from_value(value: serde_json::Value) -> Result<NetworkReply, serde_json::Error> {
+        match value {
+            serde_json::Value::Array(_) => serde_json::from_value(value).map(NetworkReply::Nets),
+            serde_json::Value::Object(ref map) if map.contains_key("no_nics") => {
+                serde_json::from_value(value)
+            }
+            _ => Err(de::Error::custom(
+                "expected a list of networks, or an object with no_nics",
+            )),
+        }
+    }
+
+    /// Returns the networks to add to the system, or `None` while the host isn't ready to
+    /// hand them out.
+    pub fn nets(self) -> Option<Vec<Network>> {
+        match self {
+            NetworkReply::Nets(nets) if nets.is_empty() => None,
+            NetworkReply::Nets(nets) => Some(nets),
+            NetworkReply::NoNics { no_nics: true } => Some(Vec::new()),
+            NetworkReply::NoNics { no_nics: false } => None,
+        }
+    }
+}
+
+/// Control of GVM guest utility message.
+#[derive(Deserialize)]
 pub struct PluginMsg {
     /// Command to run on the plugin system.
     pub cmd: GVMCmd,
-    /// Plugin name to execute on, it is recommended to use absolute path name.
+    /// Plugin name to execute on, this is either the short name of a plugin discovered in
+    /// the plugin directory, or an absolute path name.
+    #[serde(default)]
     pub plugin: String,
+    /// Logical name of the loaded plugin, ONLY used during [GVMCmd::CreatePluginLinks],
+    /// [GVMCmd::StartPlugin], [GVMCmd::PluginCmd] and [GVMCmd::StopPlugin] commands. Loading
+    /// the same plugin under several names gives independent instances of it, when absent
+    /// the plugin is loaded once under its path.
+    pub instance: Option<String>,
     /// Message field is ONLY allowed during [GVMCmd::PluginCmd] commands.
     pub msg: Option<String>,
+    /// Correlation ID chosen by the host, echoed back in the reply and included in the logs
+    /// for this request.
+    pub id: Option<String>,
+    /// Position of the command in the command stream of the host, see [check_sequence].
+    /// Commands without one are processed as they arrive, without any acknowledgement.
+    pub sequence: Option<u64>,
+    /// MAC address of the NIC, ONLY used during [GVMCmd::RemoveNetwork],
+    /// [GVMCmd::GetNetworkConfig] and [GVMCmd::SetLinkState] commands.
+    pub mac: Option<MacAddr>,
+    /// Requested state of the NIC, ONLY used during [GVMCmd::SetLinkState] commands.
+    pub state: Option<LinkState>,
+    /// Brings down the NIC even if it carries the default route of the guest, ONLY used
+    /// during [GVMCmd::SetLinkState] commands.
+    pub force: Option<bool>,
+    /// Hostname, ONLY used during [GVMCmd::SetHostname] commands.
+    pub hostname: Option<String>,
+    /// Timezone and NTP servers, ONLY used during [GVMCmd::SetTime] commands.
+    pub time: Option<Time>,
+    /// Power action to take on the system during [GVMCmd::ShutdownGuest] commands, when
+    /// absent only the guest program exits.
+    pub power: Option<PowerAction>,
+    /// Environment variables set before starting the plugin, ONLY used during
+    /// [GVMCmd::StartPlugin] commands. The values are never logged, as they may hold secrets
+    /// such as tokens.
+    pub env: Option<HashMap<String, String>>,
+    /// Working directory and resource limits applied before starting the plugin, ONLY used
+    /// during [GVMCmd::StartPlugin] commands.
+    pub limits: Option<PluginLimits>,
+    /// Starts the plugin on a thread of its own, answering right away instead of once the
+    /// plugin started, ONLY used during [GVMCmd::StartPlugin] commands. The host then polls
+    /// [GVMCmd::GetPluginStartStatus] until the plugin is ready.
+    pub background: Option<bool>,
+    /// DNS configuration, ONLY used during [GVMCmd::SetDns] commands.
+    pub dns: Option<Dns>,
+    /// Log to stream, ONLY used during [GVMCmd::StreamLog] and [GVMCmd::StopStream]
+    /// commands.
+    pub log: Option<String>,
+    /// Base64 encoded part of the plugin, ONLY used during [GVMCmd::InstallPlugin] commands.
+    pub chunk: Option<String>,
+    /// Position of `chunk` inside the plugin, starting at 0.
+    pub seq: Option<u32>,
+    /// Set on the last chunk of the plugin, which installs it.
+    pub finished: Option<bool>,
+    /// Hex encoded SHA-256 of the whole plugin, checked before installing it during
+    /// [GVMCmd::InstallPlugin] commands, or loading it during [GVMCmd::CreatePluginLinks].
+    pub checksum: Option<String>,
+    /// Hex encoded ed25519 signature of the whole plugin, required alongside `checksum` once
+    /// a plugin public key is configured.
+    pub signature: Option<String>,
+    /// Compression algorithms supported by the host, ONLY used during [GVMCmd::Negotiate]
+    /// commands.
+    pub compression: Option<Vec<String>>,
+}
+
+// Only the names of the environment variables and the length of plugin chunks are included,
+// so commands can be logged.
+impl fmt::Debug for PluginMsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let env: Option<Vec<&String>> = self.env.as_ref().map(|env| env.keys().collect());
+
+        f.debug_struct("PluginMsg")
+            .field("cmd", &self.cmd)
+            .field("plugin", &self.plugin)
+            .field("instance", &self.instance)
+            .field("msg", &self.msg)
+            .field("id", &self.id)
+            .field("sequence", &self.sequence)
+            .field("mac", &self.mac)
+            .field("state", &self.state)
+            .field("force", &self.force)
+            .field("hostname", &self.hostname)
+            .field("time", &self.time)
+            .field("power", &self.power)
+            .field("env", &env)
+            .field("limits", &self.limits)
+            .field("background", &self.background)
+            .field("dns", &self.dns)
+            .field("log", &self.log)
+            .field("chunk", &self.chunk.as_ref().map(|chunk| chunk.len()))
+            .field("seq", &self.seq)
+            .field("finished", &self.finished)
+            .field("checksum", &self.checksum)
+            .field("signature", &self.signature)
+            .field("compression", &self.compression)
+            .finish()
+    }
+}
+
+/// Checks if `name` is a legal environment variable name, made of letters, digits and
+/// underscores without starting with a digit.
+#[cfg(feature = "plugins")]
+pub fn valid_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Working directory and resource limits applied while starting a plugin. Plugins run
+/// inside the guest program, so these apply to the whole process until the plugin started.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginLimits {
+    /// Working directory of the guest program.
+    pub dir: Option<String>,
+    /// Seconds of CPU time the guest program can use, SIGXCPU is sent once reached.
+    pub cpu: Option<u64>,
+    /// Bytes of address space the guest program can map.
+    pub memory: Option<u64>,
+    /// File descriptors the guest program can have open.
+    pub files: Option<u64>,
+}
+
+/// Power actions that can be taken on the guest system.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerAction {
+    /// Powers off the system.
+    Poweroff,
+    /// Reboots the system.
+    Reboot,
+}
+
+/// Administrative states of a NIC.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkState {
+    /// Brings the NIC up.
+    Up,
+    /// Brings the NIC down.
+    Down,
+}
+
+/// DNS configuration of the guest, replacing the DNS servers of the guest configuration.
+#[derive(Deserialize, Debug)]
+pub struct Dns {
+    /// IP addresses of the nameservers.
+    pub nameservers: Vec<String>,
+    /// Domains searched when resolving short names.
+    #[serde(default)]
+    pub search: Vec<String>,
+}
+
+/// Time configuration of the guest, anything absent is left untouched.
+#[derive(Deserialize, Debug)]
+pub struct Time {
+    /// Timezone from the zoneinfo database, ie. America/Toronto.
+    pub timezone: Option<String>,
+    /// Hostnames or IP addresses of the NTP servers, replacing the configured ones.
+    pub ntp: Option<Vec<String>>,
+}
+
+/// Checks the `sequence` number of a command from the host against the `expected` one,
+/// following the last command processed. Sequence 0 restarts the command stream, ie. after
+/// the host restarted, any other number has to be the expected one, rejecting duplicates
+/// and commands skipping ahead with [GVMError::UnexpectedSequence].
+pub fn check_sequence(sequence: u64, expected: u64) -> Result<(), GVMError> {
+    if sequence != 0 && sequence != expected {
+        return Err(GVMError::UnexpectedSequence(expected));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_parsed_lowercase() {
+        let mac: MacAddr = "52:54:00:AB:cd:0F".parse().unwrap();
+        assert_eq!(mac.to_string(), "52:54:00:ab:cd:0f");
+        assert!("".parse::<MacAddr>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_mac_rejected() {
+        for mac in [
+            "52:54:00:12:34",
+            "52:54:00:12:34:56:78",
+            "52-54-00-12-34-56",
+            "52:54:00:12:34:5g",
+            "525:4:00:12:34:56",
+        ] {
+            assert!(
+                matches!(mac.parse::<MacAddr>(), Err(GVMError::InvalidMac(_))),
+                "{}",
+                mac
+            );
+        }
+        assert!(serde_json::from_str::<MacAddr>(r#""52:54""#).is_err());
+    }
+
+    #[test]
+    fn ip_with_and_without_cidr() {
+        let ip: IpCidr = "10.0.0.2".parse().unwrap();
+        assert_eq!(ip.addr, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(ip.cidr, None);
+
+        let ip: IpCidr = "10.0.0.2/24".parse().unwrap();
+        assert_eq!(ip.cidr, Some(24));
+        assert_eq!(ip.to_string(), "10.0.0.2/24");
+
+        let ip: IpCidr = "fd00::2/128".parse().unwrap();
+        assert_eq!(ip.to_string(), "fd00::2/128");
+    }
+
+    #[test]
+    fn invalid_ip_rejected() {
+        for ip in [
+            "",
+            "10.0.0",
+            "10.0.0.2/",
+            "10.0.0.2/0",
+            "10.0.0.2/33",
+            "fd00::2/129",
+            "10.0.0.2/24/8",
+        ] {
+            assert!(
+                matches!(ip.parse::<IpCidr>(), Err(GVMError::InvalidAddress(_))),
+                "{}",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_gateway_cidr_accepted() {
+        let net: Network = serde_json::from_str(
+            r#"{"mac":"52:54:00:12:34:56","ip":"10.0.0.2","gateway":"10.0.0.1/24"}"#,
+        )
+        .unwrap();
+        assert_eq!(net.ip.unwrap().cidr, None);
+        assert_eq!(net.gateway.unwrap().to_string(), "10.0.0.1/24");
+        assert_eq!(net.prefix, None);
+
+        let net: Network = serde_json::from_str(r#"{"ip":"","gateway":"10.0.0.1"}"#).unwrap();
+        assert!(net.gateway_only());
+    }
+
+    #[test]
+    fn invalid_network_addresses_rejected() {
+        for net in [
+            r#"{"mac":"52:54:00:12:34"}"#,
+            r#"{"ip":"10.0.0.256"}"#,
+            r#"{"gateway":"gateway"}"#,
+            r#"{"neighbors":[{"ip":"bad","mac":"52:54:00:12:34:56"}]}"#,
+            r#"{"routing_policy":[{"from":"10.0.0.2/40","table":100}]}"#,
+        ] {
+            assert!(serde_json::from_str::<Network>(net).is_err(), "{}", net);
+        }
+    }
+
+    #[test]
+    fn sequence_follows_last_command() {
+        assert!(check_sequence(5, 5).is_ok());
+        // Sequence 0 restarts the stream whatever came before.
+        assert!(check_sequence(0, 5).is_ok());
+        assert!(check_sequence(0, 0).is_ok());
+    }
+
+    #[test]
+    fn sequence_gap_rejected() {
+        assert!(matches!(
+            check_sequence(7, 5),
+            Err(GVMError::UnexpectedSequence(5))
+        ));
+    }
+
+    #[test]
+    fn duplicate_sequence_rejected() {
+        assert!(matches!(
+            check_sequence(4, 5),
+            Err(GVMError::UnexpectedSequence(5))
+        ));
+    }
 }
@@ -0,0 +1,234 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This is the guest local configuration of the GVM guest program.
+//!
+//! The configuration is read from [CONFIG_PATH] at startup. Every field has a default, so a
+//! missing file, or a file only overriding some of the fields, behaves like an unconfigured
+//! guest.
+//!
+//! Sending SIGHUP to the guest program reads the file again, see [Config::update] for which
+//! changes apply without a restart. The file is read again once the main loop wakes up, set
+//! [Config::comms_timeout] so it doesn't wait for the next message from the host.
+use log::LevelFilter;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+
+use crate::common::GVMError;
+use crate::linux::networking::Backend;
+use crate::logger;
+
+/// Default location of the configuration file.
+pub const CONFIG_PATH: &str = "/etc/gvm-guest/config.toml";
+
+/// Strategy used to apply netplan configuration to the system.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetplanApply {
+    /// Runs `netplan apply`, this bounces every interface on the system.
+    #[default]
+    Apply,
+    /// Runs `netplan generate` followed by `networkctl reload`, which brings up newly
+    /// added interfaces without tearing down existing connectivity. Useful for hot-add.
+    Generate,
+}
+
+/// Way of finding the device inside the guest of a NIC passed in by the host.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InterfaceMatch {
+    /// By the interface name of the NIC.
+    Name,
+    /// By the MAC address of the NIC.
+    Mac,
+    /// By the PCI address of the NIC, which stays put when the host reassigns MACs.
+    Pci,
+}
+
+/// Configuration of the GVM guest program.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// DNS servers written into the configuration of every NIC.
+    pub dns_servers: Vec<String>,
+    /// Search domains of every NIC, only applied on systems running systemd-resolved.
+    pub dns_search: Vec<String>,
+    /// Directory scanned for plugins.
+    pub plugins_dir: PathBuf,
+    /// Maximum number of plugins loaded at the same time.
+    pub max_plugins: usize,
+    /// Marker file written once networking has been initialized, recording when and for
+    /// which MAC addresses.
+    pub marker_path: PathBuf,
+    /// Health file rewritten on every iteration of the main loop, for a host watchdog. Set
+    /// comms_timeout so it also keeps being rewritten while the host sends nothing.
+    pub health_path: PathBuf,
+    /// Seconds to wait for a message from the host before waking up the main loop, when
+    /// absent reads block until the host sends something.
+    pub comms_timeout: Option<u64>,
+    /// Unix socket exchanging messages with the host instead of the host communication
+    /// device, ie. to run the guest program in userspace during development.
+    pub comms_socket: Option<PathBuf>,
+    /// TCP endpoint, in the form of host:port, exchanging messages with the host instead of
+    /// the host communication device, ie. for CI. Only supported by builds with the tcp
+    /// feature, and exclusive with comms_socket.
+    pub comms_tcp: Option<String>,
+    /// Longest message accepted from the host, in bytes, capped by the limit of the
+    /// transport. Longer messages are rejected and reported to the host, which has to shrink
+    /// or split them. When absent, the limit of the transport applies.
+    pub max_message_bytes: Option<usize>,
+    /// Times the line to the host is reopened after it closed, waiting twice as long before
+    /// every attempt, before the guest program gives up and exits. The count restarts once a
+    /// message is received again, 0 exits right away.
+    pub comms_reconnect_attempts: u32,
+    /// Announces the guest program to the host with a Hello event whenever the line to the
+    /// host opens. Turn it off for hosts failing on commands they don't know.
+    pub announce: bool,
+    /// Command used to run privileged commands.
+    pub privilege_command: String,
+    /// Strategy used to apply netplan configuration.
+    pub netplan_apply: NetplanApply,
+    /// Backends the network configuration is also written for, besides the active one, ie.
+    /// on images booting with netplan or systemd-networkd depending on a kernel flag. Only
+    /// the active backend applies the configuration, and backends without their
+    /// configuration directory are skipped.
+    pub extra_backends: Vec<Backend>,
+    /// Pre-shared key used to authenticate messages with the host, when absent messages are
+    /// exchanged without authentication.
+    pub auth_key: Option<String>,
+    /// Seconds to wait for configured NICs and bonds to come up after applying the network
+    /// configuration, 0 skips the check.
+    pub link_timeout: u64,
+    /// Seconds to wait for a DHCP lease on NICs and bonds falling back to their static
+    /// address.
+    pub dhcp_timeout: u64,
+    /// Logs the host is allowed to stream, absolute paths of files or `journal` for the
+    /// systemd journal.
+    pub log_allowlist: Vec<String>,
+    /// Lines per second sent by every log stream.
+    pub log_stream_rate: u32,
+    /// Bytes sent by a log stream before it is stopped.
+    pub log_stream_limit: u64,
+    /// Hex encoded ed25519 public key, when present every plugin needs a valid signature
+    /// from the matching private key to be installed or loaded.
+    pub plugin_public_key: Option<String>,
+    /// Order in which the selectors of a NIC are tried to find its device, selectors the
+    /// host left out and strategies missing from the list are skipped.
+    pub interface_match: Vec<InterfaceMatch>,
+    /// Minimum level of the logged records, ie. off, error, warn, info, debug or trace. Left
+    /// unused when --log-level is given, the command line taking precedence.
+    pub log_level: Option<LevelFilter>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            dns_servers: vec!["8.8.8.8".to_owned(), "8.8.4.4".to_owned()],
+            dns_search: Vec::new(),
+            plugins_dir: PathBuf::from("/usr/lib/gvm-guest/plugins"),
+            max_plugins: 16,
+            marker_path: PathBuf::from("/tmp/init-nets"),
+            health_path: PathBuf::from("/run/gvm-guest/health.json"),
+            comms_timeout: None,
+            comms_socket: None,
+            comms_tcp: None,
+            max_message_bytes: None,
+            comms_reconnect_attempts: 5,
+            announce: true,
+            privilege_command: "/bin/sudo".to_owned(),
+            netplan_apply: NetplanApply::default(),
+            extra_backends: Vec::new(),
+            auth_key: None,
+            link_timeout: 30,
+            dhcp_timeout: 30,
+            log_allowlist: Vec::new(),
+            log_stream_rate: 20,
+            log_stream_limit: 1024 * 1024,
+            plugin_public_key: None,
+            interface_match: vec![
+                InterfaceMatch::Name,
+                InterfaceMatch::Mac,
+                InterfaceMatch::Pci,
+            ],
+            log_level: None,
+        }
+    }
+}
+
+/// Replaces `$field` of `$old` with the one of `$new`, logging the change.
+macro_rules! update_field {
+    ($old:expr, $new:expr, $field:ident) => {
+        if $old.$field != $new.$field {
+            info!(
+                "Config {} changed: {:?} -> {:?}",
+                stringify!($field),
+                $old.$field,
+                $new.$field
+            );
+            $old.$field = $new.$field;
+        }
+    };
+}
+
+impl Config {
+    /// Loads the configuration from `path`, falling back to the defaults if it does not
+    /// exist.
+    pub fn load(path: &Path) -> Result<Config, GVMError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => return Err(err.into()),
+        };
+
+        toml::from_str(&contents).map_err(|err| {
+            error!("Invalid configuration {}: {}", path.display(), err);
+            GVMError::InvalidConfig
+        })
+    }
+
+    /// Applies the settings of `new` which can change while the guest program runs, logging
+    /// every change. The plugin directory, comms socket and TCP endpoint, authentication key
+    /// and plugin public key are only read at startup, changes to them are logged as
+    /// requiring a restart.
+    pub fn update(&mut self, new: Config) {
+        update_field!(self, new, dns_servers);
+        update_field!(self, new, dns_search);
+        update_field!(self, new, max_plugins);
+        update_field!(self, new, marker_path);
+        update_field!(self, new, health_path);
+        update_field!(self, new, comms_timeout);
+        update_field!(self, new, max_message_bytes);
+        update_field!(self, new, comms_reconnect_attempts);
+        update_field!(self, new, announce);
+        update_field!(self, new, privilege_command);
+        update_field!(self, new, netplan_apply);
+        update_field!(self, new, extra_backends);
+        update_field!(self, new, link_timeout);
+        update_field!(self, new, dhcp_timeout);
+        update_field!(self, new, log_allowlist);
+        update_field!(self, new, log_stream_rate);
+        update_field!(self, new, log_stream_limit);
+        update_field!(self, new, interface_match);
+        update_field!(self, new, log_level);
+        logger::set_level(self.log_level);
+
+        if self.plugins_dir != new.plugins_dir {
+            warn!("Config plugins_dir changed, requires a restart");
+        }
+        if self.comms_socket != new.comms_socket {
+            warn!("Config comms_socket changed, requires a restart");
+        }
+        if self.comms_tcp != new.comms_tcp {
+            warn!("Config comms_tcp changed, requires a restart");
+        }
+        // The key itself is never logged.
+        if self.auth_key != new.auth_key {
+            warn!("Config auth_key changed, requires a restart");
+        }
+        if self.plugin_public_key != new.plugin_public_key {
+            warn!("Config plugin_public_key changed, requires a restart");
+        }
+    }
+}
@@ -0,0 +1,41 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This holds the file helpers shared by the different parts of the guest program.
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+/// Writes `contents` to `path` atomically with the permissions `mode`, by writing a hidden
+/// temporary file inside the same directory and renaming it into place. Readers either see
+/// the old or the new file, never a partially written one.
+///
+/// The temporary file is created with `mode` from the start, so keys are never readable by
+/// others, and never through a file or symlink planted in its place beforehand. It is synced
+/// before the rename, so a crash can't leave a truncated file behind either.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>, mode: u32) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or(io::ErrorKind::InvalidInput)?
+        .to_string_lossy();
+    let tmp = path.with_file_name(".".to_owned() + &file_name + ".tmp");
+
+    // Left behind by an interrupted write.
+    let _ = fs::remove_file(&tmp);
+    let res = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents.as_ref())?;
+            file.sync_all()
+        })
+        // The umask may have stripped some of `mode`.
+        .and_then(|_| fs::set_permissions(&tmp, fs::Permissions::from_mode(mode)))
+        .and_then(|_| fs::rename(&tmp, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
@@ -14,199 +14,1027 @@
 //! and provide the following 4 functions:
 //!
 //! 1. init_net - Initializes a networking NIC that has been passed into
-//!               the system. The list of networking NIC information will
-//!               contain virtualized MAC address, IP to assign, gateway
-//!               with cidr.
+//!    the system. The list of networking NIC information will
+//!    contain virtualized MAC address, IP to assign, gateway
+//!    with cidr.
 //! 2. init_communications - Due to the nature of rust, it is better to
-//!                          implement this function in C as it allows
-//!                          for proper file descriptor control.
+//!    implement this function in C as it allows
+//!    for proper file descriptor control.
 //! 3. read_string - Reads a string from the host -> guest vm communication channel.
 //! 4. write_command - Writes a command to the host from inside the guest.
+#[macro_use]
+extern crate log;
+#[cfg(feature = "plugins")]
 extern crate dlopen;
+#[cfg(feature = "plugins")]
 #[macro_use]
 extern crate dlopen_derive;
 
+mod auth;
+mod cli;
 mod common;
+mod config;
+mod files;
+mod logger;
+#[cfg(feature = "plugins")]
+mod plugins;
+mod replay;
+#[cfg(feature = "async")]
+mod runtime;
+mod status;
 
 // Linux specific imports.
 #[cfg(target_os = "linux")]
 mod linux;
 
-use dlopen::wrapper::{Container, WrapperApi};
-use std::os::raw::c_char;
-use std::{ffi::CStr, ffi::CString, path::Path};
+use std::collections::BTreeMap;
+use std::env;
+use std::process;
 
 // Common imports for gvm-guest
-use crate::common::{Command, GVMCmd, GVMError, Network, PluginMsg};
-use std::collections::HashMap;
+use crate::cli::{Args, USAGE};
+use crate::common::{check_sequence, Command, GVMCmd, GVMError, GuestToHost, HostToGuest, Network};
+use crate::config::Config;
+#[cfg(feature = "plugins")]
+use crate::plugins::PluginManager;
+use crate::replay::{CachedReply, Delivery, ReplayCache};
+#[cfg(feature = "async")]
+use crate::runtime::main_loop;
+use crate::status::{CommsState, NetInit, NetMarker, Status};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::result::Result;
-use std::fs::File;
-use std::io::Write;
+use std::sync::atomic::AtomicBool;
+#[cfg(not(feature = "async"))]
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[cfg(target_os = "linux")]
-use crate::linux::comms::{init_communications, read_string, write_command};
+use crate::linux::comms::{
+    fits_message, init_communications, negotiate_compression, read_string, reconnect, set_auth_key,
+    set_max_message, set_read_timeout, write_command,
+};
+#[cfg(target_os = "linux")]
+use crate::linux::logstream::LogStreams;
+#[cfg(target_os = "linux")]
+use crate::linux::networking::{
+    init_net, network_config, operstate, remove_net, reset_net, set_dns, set_link_state,
+};
 #[cfg(target_os = "linux")]
-use crate::linux::networking::init_net;
-
-/// This API is exposed by shared library files on the guest in question.
-/// We use this api to expose additional, potentially proprietary guest specific
-/// APIs.
-#[derive(WrapperApi)]
-pub struct PluginApi {
-    /// Plugin initialization code, it creates a persistent state in the library.
-    ///
-    /// NOTE: The return MUST be statically allocated string as it will NOT be freed.
-    start: unsafe extern "C" fn() -> *const c_char,
-    /// Processes a command through the plugin API.
-    ///
-    /// NOTE: The return MUST be dynamically allocated string as it will be freed.
-    cmd_process: unsafe extern "C" fn(msg: *const c_char) -> *const c_char,
-    /// Shuts down the persistent state in the library.
-    ///
-    /// NOTE: The return MUST be statically allocated string as it will NOT be freed.
-    stop: unsafe extern "C" fn() -> *const c_char,
-}
-
-fn main() -> Result<(), GVMError> {
-    let mut plugins: HashMap<String, Container<PluginApi>> = HashMap::new();
-
-    init_communications()?;
-
-    if !Path::new("/tmp/init-nets").exists() {
-        write_command(Command {
+use crate::linux::selftest::self_test;
+#[cfg(target_os = "linux")]
+use crate::linux::set_dry_run;
+#[cfg(target_os = "linux")]
+use crate::linux::system::{facts, hello, power, set_hostname, set_time};
+
+/// Reads the next message from the host. Messages failing authentication or not UTF-8 are
+/// logged and dropped, returning an empty message instead. Messages too long are also
+/// reported to the host, so it shrinks or splits them rather than sending them again.
+fn read_message() -> Result<String, GVMError> {
+    match read_string() {
+        Err(err @ GVMError::MessageTooLarge(_)) => {
+            warn!("Rejected message from host: {:?}", err);
+            reject(GVMCmd::Rejected, Malformed { err, id: None });
+            Ok(String::new())
+        }
+        Err(GVMError::AuthFailed) => {
+            warn!("Rejected unauthenticated message from host");
+            Ok(String::new())
+        }
+        Err(GVMError::InvalidMessage) => {
+            warn!("Rejected invalid message from host");
+            Ok(String::new())
+        }
+        res => res,
+    }
+}
+
+/// Announces the guest program to the host, unless turned off by [Config::announce].
+fn announce(config: &Config) {
+    if !config.announce {
+        return;
+    }
+
+    send(GuestToHost::Event(Command {
+        cmd: GVMCmd::Hello,
+        resp: Some(serde_json::to_string(&hello()).unwrap()),
+        data: None,
+        finished: Some(true),
+        id: None,
+        seq: None,
+        sequence: None,
+    }));
+}
+
+/// Longest wait before reopening the line to the host.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Reopens the line to the host after it closed, for the `attempt`th time in a row. The wait
+/// before reopening doubles with every attempt, so a host taking a while to come back isn't
+/// flooded.
+fn reconnect_comms(config: &Config, attempt: u32) {
+    let delay = Duration::from_secs(1 << (attempt - 1).min(6)).min(RECONNECT_MAX_DELAY);
+    warn!(
+        "Line to the host closed, reconnecting in {} s ({}/{})",
+        delay.as_secs(),
+        attempt,
+        config.comms_reconnect_attempts
+    );
+    thread::sleep(delay);
+
+    match reconnect(config.comms_socket.as_deref(), config.comms_tcp.as_deref()) {
+        Ok(()) => {
+            set_read_timeout(config.comms_timeout);
+            info!("Reopened the line to the host");
+            announce(config);
+        }
+        Err(err) => warn!("Failed to reopen the line to the host: {:?}", err),
+    }
+}
+
+/// Number of attempts made to deliver a command to the host.
+const SEND_ATTEMPTS: u32 = 3;
+
+/// Sends `msg` to the host, retrying with a short delay on failure. A message that still
+/// can't be delivered is logged and dropped, as losing the guest program over a transient
+/// comms failure is worse than losing a single response.
+///
+/// A message which can't be serialized is replaced by a failed reply carrying the error, so
+/// the host isn't left waiting on the command.
+fn send(msg: GuestToHost) {
+    for attempt in 1..=SEND_ATTEMPTS {
+        match write_command(&msg) {
+            Ok(()) => return,
+            Err(err @ GVMError::Serialization(_)) => {
+                error!("Failed to serialize {:?}: {:?}", msg.command().cmd, err);
+                let cmd = msg.command();
+                let fallback = GuestToHost::Reply(Command {
+                    cmd: cmd.cmd,
+                    resp: Some(err.to_json()),
+                    data: None,
+                    finished: Some(false),
+                    id: cmd.id.clone(),
+                    seq: None,
+                    sequence: cmd.sequence,
+                });
+                // The fallback only carries plain strings, so it doesn't recurse further.
+                return send(fallback);
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to send {:?} (attempt {}/{}): {:?}",
+                    msg.command().cmd,
+                    attempt,
+                    SEND_ATTEMPTS,
+                    err
+                );
+                if attempt < SEND_ATTEMPTS {
+                    thread::sleep(Duration::from_millis(100 << attempt));
+                }
+            }
+        }
+    }
+}
+
+/// Splits `s` into parts of at most `size` bytes, without splitting any character.
+fn split_chars(s: &str, size: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+
+    for c in s.chars() {
+        if !part.is_empty() && part.len() + c.len_utf8() > size {
+            parts.push(mem::take(&mut part));
+        }
+        part.push(c);
+    }
+    parts.push(part);
+
+    parts
+}
+
+/// Sends the reply to a `cmd` from the host, carrying either `resp` or the structured
+/// `data`. A reply too long for a single message, such as a large plugin output, is split
+/// across several commands numbered through `seq`, and only the last one carries `finished`
+/// so the host knows when to reassemble it. Split `data` is sent serialized inside `resp`.
+/// Every part echoes the `id` and `sequence` of the command.
+fn send_reply(
+    cmd: GVMCmd,
+    resp: Option<String>,
+    data: Option<serde_json::Value>,
+    finished: bool,
+    id: Option<String>,
+    sequence: Option<u64>,
+) {
+    let reply = GuestToHost::Reply(Command {
+        cmd,
+        resp,
+        data,
+        finished: Some(finished),
+        id,
+        seq: None,
+        sequence,
+    });
+
+    if fits_message(&reply) {
+        return send(reply);
+    }
+    let resp = match (&reply.command().resp, &reply.command().data) {
+        (Some(resp), _) => resp.clone(),
+        (None, Some(data)) => data.to_string(),
+        (None, None) => return send(reply),
+    };
+
+    // Escaping and authentication grow the response by an amount depending on its contents,
+    // so the parts shrink until every one of them fits.
+    let mut size = resp.len();
+    loop {
+        size /= 2;
+        if size == 0 {
+            warn!("Unable to split {:?} response, sending it whole", cmd);
+            return send(reply);
+        }
+
+        let parts = split_chars(&resp, size);
+        let last = parts.len() - 1;
+        let frames: Vec<GuestToHost> = parts
+            .into_iter()
+            .enumerate()
+            .map(|(seq, part)| {
+                GuestToHost::Reply(Command {
+                    cmd,
+                    resp: Some(part),
+                    data: None,
+                    finished: if seq == last { Some(finished) } else { None },
+                    id: reply.command().id.clone(),
+                    seq: Some(seq as u32),
+                    sequence,
+                })
+            })
+            .collect();
+
+        if frames.iter().all(fits_message) {
+            for frame in frames {
+                send(frame);
+            }
+            return;
+        }
+    }
+}
+
+/// Message from the host which was rejected, ie. valid JSON but not the shape of any known
+/// message, or too long to be read.
+struct Malformed {
+    /// Reason the message was rejected.
+    err: GVMError,
+    /// Correlation ID of the message, if it carried one.
+    id: Option<String>,
+}
+
+/// Parses a message from the host. Messages which aren't JSON at all, such as empty reads,
+/// are ignored and return `None`. Valid JSON of the wrong shape is logged and returned as
+/// [Malformed], so it can be reported back to the host.
+fn parse_message(msg: &str) -> Option<Result<HostToGuest, Malformed>> {
+    let value: serde_json::Value = serde_json::from_str(msg).ok()?;
+    let id = value
+        .get("payload")
+        .unwrap_or(&value)
+        .get("id")
+        .and_then(|id| id.as_str())
+        .map(|id| id.to_owned());
+
+    Some(HostToGuest::from_value(value).map_err(|err| {
+        warn!("Malformed message from host: {}", err);
+        Malformed {
+            err: GVMError::MalformedMessage(err.to_string()),
+            id,
+        }
+    }))
+}
+
+/// Reports a `malformed` message back to the host as a failed `cmd`, so it learns its
+/// message was not processed.
+fn reject(cmd: GVMCmd, malformed: Malformed) {
+    send(GuestToHost::Reply(Command {
+        cmd,
+        resp: Some(malformed.err.to_json()),
+        data: None,
+        finished: Some(false),
+        id: malformed.id,
+        seq: None,
+        sequence: None,
+    }));
+}
+
+/// Provides the operational state of every interface in `ifaces`, keyed by name.
+fn link_states(ifaces: Vec<String>) -> BTreeMap<String, String> {
+    ifaces
+        .into_iter()
+        .map(|iface| {
+            let state = operstate(&iface);
+            (iface, state)
+        })
+        .collect()
+}
+
+/// Configures the networks listed as JSON in the file at `path`, the same way as networks
+/// sent by the host, and provides the state of the configured interfaces. Used to set up
+/// networking once, such as while building an image, without talking to the host.
+fn apply_file(path: &Path, config: &Config) -> Result<BTreeMap<String, String>, GVMError> {
+    let contents = fs::read_to_string(path)?;
+    let nets: Vec<Network> = serde_json::from_str(&contents).map_err(|err| {
+        error!("Invalid networks {}: {}", path.display(), err);
+        GVMError::InvalidNetworkFile(err.to_string())
+    })?;
+
+    let ifaces = init_net(&nets, config, &mut |msg| info!("{}", msg))?;
+    info!("Initialized nets: {:#?}", nets);
+    Ok(link_states(ifaces))
+}
+
+/// Writes the `marker` file at `path`, so networking is not initialized again on the next
+/// run.
+fn write_marker(path: &Path, marker: &NetMarker) -> Result<(), GVMError> {
+    let mut file = File::create(path).inspect_err(|err| {
+        error!("Failed to create marker {}: {}", path.display(), err);
+    })?;
+    let contents = serde_json::to_string(marker).unwrap();
+    file.write_all(contents.as_bytes()).inspect_err(|err| {
+        error!("Failed to write marker {}: {}", path.display(), err);
+    })?;
+    Ok(())
+}
+
+/// Removes the marker file at `path`, so networking is initialized again on the next run.
+fn remove_marker(path: &Path) -> Result<(), GVMError> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            error!("Failed to remove marker {}: {}", path.display(), err);
+            Err(err.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Delay before asking the host for networks again, while it has none ready.
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+    if args.version {
+        println!("gvm-guest {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    logger::init(args.log_level);
+    set_dry_run(args.dry_run);
+
+    if let Err(err) = run(&args) {
+        error!("Exiting on error: {:?}", err);
+        process::exit(err.exit_code());
+    }
+}
+
+/// Runs the guest program with the command line `args`, until the host shuts the guest
+/// down or an unrecoverable error occurs.
+fn run(args: &Args) -> Result<(), GVMError> {
+    let config = Config::load(&args.config)?;
+    logger::set_level(config.log_level);
+
+    // One-shot mode prints the interface states, or the error, and exits. The marker file is
+    // left alone, so the host can still configure networking on the next boot.
+    if let Some(path) = &args.apply_file {
+        match apply_file(path, &config) {
+            Ok(states) => println!("{}", serde_json::to_string(&states).unwrap()),
+            Err(err) => {
+                println!("{}", err.to_json());
+                process::exit(err.exit_code());
+            }
+        }
+        return Ok(());
+    }
+
+    // Set by SIGHUP, the configuration is reloaded before handling the next command. Only
+    // reads with comms_timeout set are interrupted by the signal, others are restarted, so
+    // the reload waits for the next message from the host. The async main loop wakes up on
+    // the signal instead.
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload))?;
+    #[cfg(not(feature = "async"))]
+    if config.comms_timeout.is_none() {
+        info!("comms_timeout is not set, configuration reloads wait for the host");
+    }
+    #[cfg(feature = "plugins")]
+    let plugins = PluginManager::new(
+        &config.plugins_dir,
+        config.max_plugins,
+        config.plugin_public_key.as_deref(),
+    )?;
+
+    // Whatever the reason, the line to the host is not open.
+    init_communications(config.comms_socket.as_deref(), config.comms_tcp.as_deref())
+        .map_err(|_| GVMError::CommsClosed)?;
+    set_read_timeout(config.comms_timeout);
+    set_max_message(config.max_message_bytes);
+    if let Some(key) = &config.auth_key {
+        set_auth_key(key);
+    }
+    announce(&config);
+
+    let mut net_init = NetInit::AlreadyInitialized;
+    // Time spent initializing networking, when it was initialized by this run.
+    let mut net_init_time = None;
+    // Networks initialized by this run, recorded inside the marker file.
+    let mut marker = None;
+
+    if !config.marker_path.exists() {
+        send(GuestToHost::Request(Command {
             cmd: GVMCmd::GetNetwork,
             resp: None,
+            data: None,
             finished: None,
-        })?;
+            id: None,
+            seq: None,
+            sequence: None,
+        }));
+        // Whether a malformed network reply was already reported to the host.
+        let mut reported = false;
         loop {
-            let nets_res: Result<Vec<Network>, serde_json::Error> =
-                serde_json::from_str(&read_string()?);
+            let reply = match parse_message(&read_message()?) {
+                Some(Ok(HostToGuest::Network(reply))) => reply,
+                Some(Ok(HostToGuest::Command(command))) => {
+                    warn!(
+                        "Ignoring command until networking is initialized: {:?}",
+                        command
+                    );
+                    continue;
+                }
+                // Only reported once, a host stuck sending the wrong shape keeps being
+                // logged without flooding it with errors.
+                Some(Err(malformed)) => {
+                    if !reported {
+                        reported = true;
+                        reject(GVMCmd::GetNetwork, malformed);
+                    }
+                    continue;
+                }
+                None => continue,
+            };
 
-            if nets_res.is_err() {
-                continue;
-            }
+            // An empty list means the host has no NICs ready yet, ask again later rather
+            // than marking networking as initialized.
+            let nets = match reply.nets() {
+                Some(nets) => nets,
+                None => {
+                    info!("Host has no networks ready, polling again");
+                    thread::sleep(NETWORK_POLL_INTERVAL);
+                    send(GuestToHost::Request(Command {
+                        cmd: GVMCmd::GetNetwork,
+                        resp: None,
+                        data: None,
+                        finished: None,
+                        id: None,
+                        seq: None,
+                        sequence: None,
+                    }));
+                    continue;
+                }
+            };
 
-            let nets = nets_res.unwrap();
-            let res = init_net(&nets);
             let mut resp = None;
             let mut fin = Some(true);
 
-            if res.is_err() {
-                resp = Some(res.unwrap_err().to_string());
-                fin = Some(false);
-            }
+            let mut progress = |msg: &str| {
+                send(GuestToHost::Progress(Command {
+                    cmd: GVMCmd::GetNetwork,
+                    resp: Some(msg.to_owned()),
+                    data: None,
+                    finished: None,
+                    id: None,
+                    seq: None,
+                    sequence: None,
+                }))
+            };
+
+            net_init = NetInit::Initialized;
+            let started = Instant::now();
+            let applied = init_net(&nets, &config, &mut progress);
+            net_init_time = Some(started.elapsed());
+            let ifaces = match applied {
+                Ok(ifaces) => Some(ifaces),
+                Err(err) => {
+                    resp = Some(err.to_json());
+                    fin = Some(false);
+                    net_init = NetInit::Failed;
+                    None
+                }
+            };
 
-            write_command(Command {
+            send(GuestToHost::Reply(Command {
                 cmd: GVMCmd::GetNetwork,
-                resp: resp,
+                resp,
+                data: None,
                 finished: fin,
-            })?;
+                id: None,
+                seq: None,
+                sequence: None,
+            }));
 
-            println!("Initialized nets: {:#?}", nets);
+            // Tells the host it can start relying on the guest network.
+            if let Some(ifaces) = ifaces {
+                let states = link_states(ifaces);
+                send(GuestToHost::Event(Command {
+                    cmd: GVMCmd::NetworkReady,
+                    resp: Some(serde_json::to_string(&states).unwrap()),
+                    data: None,
+                    finished: Some(true),
+                    id: None,
+                    seq: None,
+                    sequence: None,
+                }));
+            }
+
+            info!("Initialized nets: {:#?}", nets);
+            marker = Some(NetMarker::new(&nets));
             break;
         }
     }
 
-    let mut file = File::create("/tmp/init-nets").unwrap();
-    let _ = file.write_all(b"Inited networkined");
+    // Only written once the host got the success reply. A dry run changed nothing, and a
+    // failed initialization is retried, so networking is left to be initialized by the next
+    // run.
+    if let (false, NetInit::Initialized, Some(marker)) = (args.dry_run, net_init, &marker) {
+        write_marker(&config.marker_path, marker)?;
+    }
+
+    let mut status = Status::new(net_init);
+    if let Some(elapsed) = net_init_time {
+        status.record(GVMCmd::GetNetwork, elapsed, net_init == NetInit::Failed);
+    }
+    let agent = Agent {
+        config,
+        config_path: args.config.clone(),
+        dry_run: args.dry_run,
+        #[cfg(feature = "plugins")]
+        plugins,
+        status,
+        streams: LogStreams::new(),
+        replay: ReplayCache::new(),
+        pings: 0,
+        next_sequence: 0,
+    };
+
+    main_loop(agent, reload)
+}
+
+/// Answers the commands of the host one after the other, until the host shuts the guest
+/// down. The configuration is read again before waiting for the next message once `reload`
+/// is set.
+#[cfg(not(feature = "async"))]
+fn main_loop(mut agent: Agent, reload: Arc<AtomicBool>) -> Result<(), GVMError> {
+    // Times in a row the line to the host was reopened without receiving anything.
+    let mut reconnects = 0;
 
     loop {
-        let command_res: Result<PluginMsg, serde_json::Error> =
-            serde_json::from_str(&read_string()?);
+        if reload.swap(false, Ordering::Relaxed) {
+            agent.reload();
+        }
+        agent.write_health(CommsState::Open);
+
+        let message = match read_message() {
+            Ok(message) => message,
+            // A reopened line which closes again right away counts as another attempt.
+            Err(GVMError::CommsClosed) if reconnects < agent.config.comms_reconnect_attempts => {
+                agent.write_health(CommsState::Reconnecting);
+                reconnects += 1;
+                reconnect_comms(&agent.config, reconnects);
+                continue;
+            }
+            Err(err) => {
+                agent.write_health(CommsState::Closed);
+                return Err(err);
+            }
+        };
+        if !message.is_empty() {
+            reconnects = 0;
+        }
 
-        if command_res.is_err() {
-            continue;
+        if agent.handle(&message)? == Flow::Shutdown {
+            return Ok(());
         }
+    }
+}
 
-        let command = command_res.unwrap();
-        let mut fin = false;
-        let mut resp: Option<String> = None;
+/// State of the guest program once networking is initialized, carried from one command of
+/// the host to the next.
+struct Agent {
+    /// Guest configuration, replaced by [Agent::reload].
+    config: Config,
+    /// File the configuration is read from.
+    config_path: PathBuf,
+    /// Whether the changes to the system are only logged.
+    dry_run: bool,
+    #[cfg(feature = "plugins")]
+    plugins: PluginManager,
+    status: Status,
+    streams: LogStreams,
+    replay: ReplayCache,
+    /// Monotonic counter of pings answered, lets the host detect missed heartbeats.
+    pings: u64,
+    /// Sequence number expected on the next sequenced command.
+    next_sequence: u64,
+}
 
-        match command.cmd {
-            GVMCmd::CreatePluginLinks => {
-                if !plugins.contains_key(&command.plugin) {
-                    let name = &command.plugin;
-                    if !Path::new(name).exists() {
-                        println!("Got error: {:?}", GVMError::PluginNotFound);
-                        resp = Some(GVMError::PluginNotFound.to_string());
-                    } else {
-                        let api = unsafe { Container::load(&name) };
-                        if api.is_err() {
-                            resp = Some(GVMError::PluginNotFound.to_string());
-                        } else {
-                            plugins.insert(name.to_string(), api.unwrap());
-                            fin = true;
-                        }
-                    }
-                } else {
-                    println!("Plugin already loaded");
-                    resp = Some(GVMError::PluginLoaded.to_string());
+/// What the main loop does once a message from the host is handled.
+#[derive(Debug, PartialEq, Eq)]
+enum Flow {
+    /// Waits for the next message.
+    Continue,
+    /// Stops, the host shut the guest down.
+    Shutdown,
+}
+
+impl Agent {
+    /// Reads the configuration again, keeping the previous one if the file is invalid.
+    fn reload(&mut self) {
+        info!("Reloading configuration");
+        match Config::load(&self.config_path) {
+            Ok(new) => {
+                self.config.update(new);
+                set_read_timeout(self.config.comms_timeout);
+                set_max_message(self.config.max_message_bytes);
+                #[cfg(feature = "plugins")]
+                self.plugins.set_max_loaded(self.config.max_plugins);
+            }
+            Err(err) => warn!("Keeping previous configuration: {:?}", err),
+        }
+    }
+
+    /// Rewrites the health file, with the line to the host in the `comms` state.
+    fn write_health(&mut self, comms: CommsState) {
+        #[cfg(feature = "plugins")]
+        let loaded = self.plugins.loaded_count();
+        #[cfg(not(feature = "plugins"))]
+        let loaded = 0;
+        self.status
+            .write_health(&self.config.health_path, loaded, comms);
+    }
+
+    /// Handles the `message` read from the host, empty when the read timed out. Fails when
+    /// the guest program can't go on, ie. powering off the system failed.
+    fn handle(&mut self, message: &str) -> Result<Flow, GVMError> {
+        if !message.is_empty() {
+            self.status.touch();
+        }
+
+        let command = match parse_message(message) {
+            Some(Ok(HostToGuest::Command(command))) => command,
+            Some(Ok(HostToGuest::Network(reply))) => {
+                warn!("Ignoring unexpected networks: {:?}", reply);
+                return Ok(Flow::Continue);
+            }
+            Some(Err(malformed)) => {
+                reject(GVMCmd::Rejected, malformed);
+                return Ok(Flow::Continue);
+            }
+            None => return Ok(Flow::Continue),
+        };
+
+        // A command delivered again by the transport is answered without executing it twice.
+        // Pings have no side effect, and are answered every time.
+        if let (Some(id), false) = (&command.id, matches!(command.cmd, GVMCmd::Ping)) {
+            match self.replay.deliver(id) {
+                Delivery::New => {}
+                Delivery::Running => {
+                    warn!(
+                        "[{}] Dropping duplicate {:?}, still running",
+                        id, command.cmd
+                    );
+                    return Ok(Flow::Continue);
+                }
+                Delivery::Done(reply) => {
+                    warn!("[{}] Duplicate {:?}, resending its reply", id, command.cmd);
+                    send_reply(
+                        reply.cmd,
+                        reply.resp,
+                        reply.data,
+                        reply.finished,
+                        command.id,
+                        command.sequence,
+                    );
+                    return Ok(Flow::Continue);
                 }
             }
-            GVMCmd::StartPlugin => {
-                if plugins.contains_key(&command.plugin) {
-                    let c_buf: *const c_char = unsafe { plugins[&command.plugin].start() };
-                    if !c_buf.is_null() {
-                        let c_str: &CStr = unsafe { CStr::from_ptr(c_buf) };
-                        let str_slice: &str = c_str.to_str().unwrap();
-                        let str_buf: String = str_slice.to_owned();
-                        resp = Some(str_buf);
-                    }
-                    fin = true;
-                } else {
-                    println!("Plugin not loaded");
-                    resp = Some(GVMError::PluginNotFound.to_string());
+        }
+
+        // Sequenced commands are acknowledged on receipt, and only processed in order. Commands
+        // rejected out of order never ran, so they run when delivered again in order.
+        if let Some(sequence) = command.sequence {
+            if let Err(err) = check_sequence(sequence, self.next_sequence) {
+                warn!(
+                    "Rejecting {:?} with sequence {}, expected {}",
+                    command.cmd, sequence, self.next_sequence
+                );
+                if let (Some(id), false) = (&command.id, matches!(command.cmd, GVMCmd::Ping)) {
+                    self.replay.forget(id);
                 }
+                send(GuestToHost::Reply(Command {
+                    cmd: command.cmd,
+                    resp: Some(err.to_json()),
+                    data: None,
+                    finished: Some(false),
+                    id: command.id,
+                    seq: None,
+                    sequence: Some(sequence),
+                }));
+                return Ok(Flow::Continue);
             }
-            GVMCmd::PluginCmd => {
-                if plugins.contains_key(&command.plugin) {
-                    if command.msg.is_some() {
-                        let cstr = CString::new(command.msg.unwrap()).unwrap();
-                        let c_buf: *const c_char =
-                            unsafe { plugins[&command.plugin].cmd_process(cstr.as_ptr()) };
-                        if !c_buf.is_null() {
-                            let c_str: &CStr = unsafe { CStr::from_ptr(c_buf) };
-                            let str_slice: &str = c_str.to_str().unwrap();
-                            let str_buf: String = str_slice.to_owned();
-                            resp = Some(str_buf);
-                        }
-                        fin = true;
+
+            self.next_sequence = sequence + 1;
+            send(GuestToHost::Ack(Command {
+                cmd: command.cmd,
+                resp: None,
+                data: None,
+                finished: None,
+                id: command.id.clone(),
+                seq: None,
+                sequence: Some(sequence),
+            }));
+        }
+
+        // Liveness checks are answered before doing any other work.
+        if matches!(command.cmd, GVMCmd::Ping) {
+            self.pings += 1;
+            send(GuestToHost::Reply(Command {
+                cmd: GVMCmd::Ping,
+                resp: Some(self.pings.to_string()),
+                data: None,
+                finished: Some(true),
+                id: command.id,
+                seq: None,
+                sequence: command.sequence,
+            }));
+            return Ok(Flow::Continue);
+        }
+
+        self.status.commands += 1;
+        let tag = command.id.clone().unwrap_or_else(|| "-".to_owned());
+        info!("[{}] Handling {:?}", tag, command.cmd);
+
+        // Structured response of the command, only set by plugins.
+        #[cfg_attr(not(feature = "plugins"), allow(unused_mut))]
+        let mut data = None;
+
+        // Without plugin support, the plugin commands fall through to the unsupported arm.
+        let started = Instant::now();
+        let res = match command.cmd {
+            // Only the commands controlling the guest program run while it is paused.
+            cmd if self.status.paused
+                && !matches!(cmd, GVMCmd::Pause | GVMCmd::Resume | GVMCmd::GetStatus) =>
+            {
+                warn!("[{}] Paused, rejecting {:?}", tag, cmd);
+                Err(GVMError::AgentPaused)
+            }
+            GVMCmd::Pause => {
+                info!("[{}] Pausing command execution", tag);
+                self.status.paused = true;
+                Ok(None)
+            }
+            GVMCmd::Resume => {
+                info!("[{}] Resuming command execution", tag);
+                self.status.paused = false;
+                Ok(None)
+            }
+            #[cfg(feature = "plugins")]
+            GVMCmd::ListAvailablePlugins => {
+                self.plugins.discover();
+                Ok(Some(
+                    serde_json::to_string(&self.plugins.available()).unwrap(),
+                ))
+            }
+            #[cfg(feature = "plugins")]
+            GVMCmd::CreatePluginLinks => self
+                .plugins
+                .create_links(
+                    &command.plugin,
+                    command.instance.as_deref(),
+                    command.checksum.as_deref(),
+                    command.signature.as_deref(),
+                )
+                .map(|_| None),
+            #[cfg(feature = "plugins")]
+            GVMCmd::StartPlugin => self.plugins.start(
+                &command.plugin,
+                command.instance.as_deref(),
+                command.env.as_ref(),
+                command.limits.as_ref(),
+                command.background.unwrap_or(false),
+            ),
+            #[cfg(feature = "plugins")]
+            GVMCmd::GetPluginStartStatus => self
+                .plugins
+                .start_state(&command.plugin, command.instance.as_deref())
+                .map(|state| Some(serde_json::to_string(&state).unwrap())),
+            #[cfg(feature = "plugins")]
+            GVMCmd::PluginCmd => match command.msg {
+                Some(msg) => self
+                    .plugins
+                    .cmd_process(&command.plugin, command.instance.as_deref(), msg)
+                    .map(|value| {
+                        data = value;
+                        None
+                    }),
+                None => Ok(None),
+            },
+            #[cfg(feature = "plugins")]
+            GVMCmd::StopPlugin => self
+                .plugins
+                .stop(&command.plugin, command.instance.as_deref()),
+            #[cfg(feature = "plugins")]
+            GVMCmd::InstallPlugin => match &command.chunk {
+                Some(chunk) => self
+                    .plugins
+                    .install(
+                        &command.plugin,
+                        chunk,
+                        command.seq.unwrap_or(0),
+                        command.finished.unwrap_or(true),
+                        command.checksum.as_deref(),
+                        command.signature.as_deref(),
+                    )
+                    .map(|_| None),
+                None => Err(GVMError::InvalidMessage),
+            },
+            GVMCmd::RemoveNetwork => match &command.mac {
+                Some(mac) => remove_net(mac, &self.config).map(|removed| {
+                    if removed {
+                        None
+                    } else {
+                        Some("NotConfigured".to_owned())
                     }
-                } else {
-                    println!("Plugin not loaded");
-                    resp = Some(GVMError::PluginNotFound.to_string());
+                }),
+                None => Err(GVMError::InvalidMessage),
+            },
+            GVMCmd::ResetNetwork => reset_net(&self.config).and_then(|files| {
+                if !self.dry_run {
+                    remove_marker(&self.config.marker_path)?;
                 }
+                self.status.net_init = NetInit::Reset;
+                Ok(Some(serde_json::to_string(&files).unwrap()))
+            }),
+            GVMCmd::SetLinkState => match (&command.mac, command.state) {
+                (Some(mac), Some(state)) => {
+                    set_link_state(mac, state, command.force.unwrap_or(false), &self.config)
+                        .map(|_| None)
+                }
+                _ => Err(GVMError::InvalidMessage),
+            },
+            GVMCmd::GetNetworkInitStatus => {
+                let report = self.status.net_init_report(&self.config.marker_path);
+                Ok(Some(serde_json::to_string(&report).unwrap()))
+            }
+            GVMCmd::GetNetworkConfig => network_config(command.mac.as_deref(), &self.config)
+                .map(|configs| Some(serde_json::to_string(&configs).unwrap())),
+            GVMCmd::GetStatus => {
+                #[cfg(feature = "plugins")]
+                let (loaded, limits) = (self.plugins.loaded_count(), self.plugins.limits());
+                #[cfg(not(feature = "plugins"))]
+                let (loaded, limits) = (0, BTreeMap::new());
+                let report = self.status.report(loaded, limits);
+                Ok(Some(serde_json::to_string(&report).unwrap()))
+            }
+            GVMCmd::SetHostname => match &command.hostname {
+                Some(hostname) => set_hostname(hostname, &self.config).map(|_| None),
+                None => Err(GVMError::InvalidMessage),
+            },
+            GVMCmd::SetTime => match &command.time {
+                Some(time) => set_time(time, &self.config).map(|_| None),
+                None => Err(GVMError::InvalidMessage),
+            },
+            GVMCmd::SetDns => match &command.dns {
+                Some(dns) => set_dns(dns, &self.config).map(|_| None),
+                None => Err(GVMError::InvalidMessage),
+            },
+            GVMCmd::SelfTest => {
+                // Exercises the guest -> host direction, the host -> guest direction already
+                // worked as this command was received.
+                let comms = write_command(&GuestToHost::Progress(Command {
+                    cmd: GVMCmd::SelfTest,
+                    resp: None,
+                    data: None,
+                    finished: None,
+                    id: command.id.clone(),
+                    seq: None,
+                    sequence: None,
+                }));
+                let report = self_test(&self.config, comms);
+                Ok(Some(serde_json::to_string(&report).unwrap()))
             }
-            GVMCmd::StopPlugin => {
-                if plugins.contains_key(&command.plugin) {
-                    unsafe { plugins[&command.plugin].stop() };
-                    let c_buf: *const c_char = unsafe { plugins[&command.plugin].stop() };
-                    if !c_buf.is_null() {
-                        let c_str: &CStr = unsafe { CStr::from_ptr(c_buf) };
-                        let str_slice: &str = c_str.to_str().unwrap();
-                        let str_buf: String = str_slice.to_owned();
-                        resp = Some(str_buf);
+            GVMCmd::GetFacts => Ok(Some(serde_json::to_string(&facts()).unwrap())),
+            GVMCmd::Negotiate => {
+                let offered = command.compression.as_deref().unwrap_or_default();
+                let compression = negotiate_compression(offered);
+                info!("Negotiated compression: {}", compression.unwrap_or("none"));
+                Ok(Some(
+                    serde_json::json!({ "compression": compression }).to_string(),
+                ))
+            }
+            // Once started, the stream sends the lines and the final reply itself. A duplicate
+            // is answered like a second stream of the same log.
+            GVMCmd::StreamLog => match &command.log {
+                Some(log) => {
+                    match self.streams.start(
+                        log,
+                        command.id.clone(),
+                        command.sequence,
+                        &self.config,
+                        send,
+                    ) {
+                        Ok(()) => {
+                            self.status.record(command.cmd, started.elapsed(), false);
+                            if let Some(id) = &command.id {
+                                self.replay.finish(
+                                    id,
+                                    CachedReply {
+                                        cmd: command.cmd,
+                                        resp: Some(GVMError::StreamActive(log.clone()).to_json()),
+                                        data: None,
+                                        finished: false,
+                                    },
+                                );
+                            }
+                            return Ok(Flow::Continue);
+                        }
+                        Err(err) => Err(err),
                     }
-                    fin = true;
-                } else {
-                    println!("Plugin not loaded");
-                    resp = Some(GVMError::PluginNotFound.to_string());
                 }
-            }
+                None => Err(GVMError::InvalidMessage),
+            },
+            GVMCmd::StopStream => match &command.log {
+                Some(log) if self.streams.stop(log) => Ok(None),
+                Some(_) => Ok(Some("NotStreaming".to_owned())),
+                None => {
+                    self.streams.stop_all();
+                    Ok(None)
+                }
+            },
             GVMCmd::ShutdownGuest => {
-                break;
+                self.streams.stop_all();
+                if let Some(action) = command.power {
+                    #[cfg(feature = "plugins")]
+                    self.plugins.stop_all();
+                    info!("[{}] Shutting down system: {:?}", tag, action);
+                    power(action, &self.config)?;
+                }
+                return Ok(Flow::Shutdown);
             }
             _ => {
-                println!("Unsupported plugin command: {:#?}", command);
-                resp = Some(GVMError::PluginCommandNotSupported.to_string());
+                warn!("[{}] Unsupported plugin command: {:#?}", tag, command);
+                Err(GVMError::PluginCommandNotSupported)
             }
         };
-        write_command(Command {
-            cmd: command.cmd,
-            resp: resp,
-            finished: Some(fin),
-        })?;
-    }
 
-    Ok(())
+        self.status
+            .record(command.cmd, started.elapsed(), res.is_err());
+
+        // Commands rejected while paused never ran, so they run when delivered again.
+        if let Some(id) = &command.id {
+            if matches!(res, Err(GVMError::AgentPaused)) {
+                self.replay.forget(id);
+            }
+        }
+
+        let (resp, fin) = match res {
+            Ok(resp) => (resp, true),
+            Err(err) => {
+                error!("[{}] Got error: {:?}", tag, err);
+                self.status.last_error = Some(err.to_string());
+                (Some(err.to_json()), false)
+            }
+        };
+
+        if let Some(id) = &command.id {
+            self.replay.finish(
+                id,
+                CachedReply {
+                    cmd: command.cmd,
+                    resp: resp.clone(),
+                    data: data.clone(),
+                    finished: fin,
+                },
+            );
+        }
+        send_reply(command.cmd, resp, data, fin, command.id, command.sequence);
+
+        Ok(Flow::Continue)
+    }
 }
@@ -2,49 +2,751 @@
 // SPDX-License-Identifier: GPL-2.0
 //! This handles the low level host -> guest communications.
 //!
+//! Messages go through the host communication device of the C layer, or through a unix
+//! socket when one is configured, ie. to run the guest program in userspace during
+//! development. Builds with the `tcp` feature can also connect to the host over TCP, ie.
+//! for CI. Every message on a socket is prefixed by its length, see [write_frame].
+//!
 //! NOTE: ALL OF THESE FUNCTIONS HAVE POTENTIALLY DANGEROUS SIDE EFFECTS.
 use std::ffi::{CStr, CString};
+use std::io::{self, Read, Write};
+#[cfg(feature = "tcp")]
+use std::net::TcpStream;
 use std::os::raw::c_char;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::result::Result;
+#[cfg(feature = "compression")]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+
+use crate::auth;
+use crate::common::{GVMError, GuestToHost};
+#[cfg(feature = "compression")]
+use base64::{engine::general_purpose::STANDARD, Engine};
+#[cfg(feature = "compression")]
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+#[cfg(feature = "compression")]
+use serde::{Deserialize, Serialize};
+
+/// Pre-shared key authenticating every message exchanged with the host.
+static AUTH_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Held while writing to the C layer, as log streams write from their own threads.
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Socket replacing the C layer, when configured. Replaced when reconnecting, while log
+/// streams may still be writing to the previous one.
+static SOCKET: RwLock<Option<Arc<Socket>>> = RwLock::new(None);
+
+/// Longest message exchanged through a socket.
+const SOCKET_MAX_LEN: usize = 64 * 1024;
+
+/// Longest message accepted from the host, 0 when only the limit of the transport applies.
+static MAX_MESSAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether long messages sent to the host are compressed, see [negotiate_compression].
+#[cfg(feature = "compression")]
+static COMPRESS: AtomicBool = AtomicBool::new(false);
+
+/// Messages sent to the host are only compressed above this length, shorter ones gain too
+/// little to be worth it.
+#[cfg(feature = "compression")]
+const COMPRESS_THRESHOLD: usize = 1024;
+
+/// Longest decompressed message, so a small message can't expand without bounds.
+#[cfg(feature = "compression")]
+const DECOMPRESSED_MAX_LEN: usize = 16 * 1024 * 1024;
+
+/// Compressed message, wrapping the base64 encoded gzip of the message. Base64 is needed as
+/// the C layer carries NUL terminated strings.
+#[cfg(feature = "compression")]
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+enum Envelope {
+    Compressed(String),
+}
+
+/// Connection to the host replacing the C layer.
+enum Socket {
+    /// Unix socket, see [crate::config::Config::comms_socket].
+    Unix(UnixStream),
+    /// TCP connection, see [crate::config::Config::comms_tcp].
+    #[cfg(feature = "tcp")]
+    Tcp(TcpStream),
+}
 
-use crate::common::{Command, GVMError};
+impl Socket {
+    /// Provides how long reads wait for the host, `None` waits forever.
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        match self {
+            Socket::Unix(stream) => stream.read_timeout(),
+            #[cfg(feature = "tcp")]
+            Socket::Tcp(stream) => stream.read_timeout(),
+        }
+    }
 
+    /// Sets how long reads wait for the host, `None` waits forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Unix(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tcp")]
+            Socket::Tcp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for &Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Unix(stream) => (&*stream).read(buf),
+            #[cfg(feature = "tcp")]
+            Socket::Tcp(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Unix(stream) => (&*stream).write(buf),
+            #[cfg(feature = "tcp")]
+            Socket::Tcp(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Unix(stream) => (&*stream).flush(),
+            #[cfg(feature = "tcp")]
+            Socket::Tcp(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+#[cfg(not(test))]
 extern "C" {
     /// Initializes the communication layer, this has a side effect of opening a long
     /// lasting file descriptor.
     fn init_comms() -> i32;
-    /// This reads a string from the buffer, this cannot surpass 1024 characters at the
-    /// moment.
+    /// This reads a string from the host into a static buffer owned by the C layer, and
+    /// returns a pointer to it, or NULL if the communication layer is not initialized or the
+    /// host hung up. The buffer must NOT be freed, and is overwritten by the next call, so it
+    /// has to be copied out right away. The string cannot surpass [comms_max_len] characters
+    /// at the moment.
     fn read_comms() -> *const c_char;
+    /// Maximum length of a string returned by `read_comms`.
+    fn comms_max_len() -> usize;
     /// This writes the string into the host communications.
     fn write_comms(str: *const c_char) -> i32;
+    /// Sets the milliseconds `read_comms` waits for the host, negative values wait forever.
+    fn set_comms_timeout(ms: i32);
+    /// Closes the communication layer, so `init_comms` opens it again.
+    fn close_comms();
 }
 
-/// Initializes the host -> guest communication line.
-pub fn init_communications() -> Result<(), GVMError> {
-    if unsafe { init_comms() } == 1 {
+#[cfg(test)]
+use stub::{close_comms, comms_max_len, init_comms, read_comms, set_comms_timeout, write_comms};
+
+/// In-memory stand-in for the C layer, so the wrappers can be tested without the host
+/// communication device. Messages queued with [stub::push] are returned by `read_comms`, and
+/// everything passed to `write_comms` is kept for [stub::sent]. The state is per thread, so
+/// every test gets its own channel.
+#[cfg(test)]
+mod stub {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+
+    /// Same limit as COMMS_MAX_LEN inside the C layer.
+    const MAX_LEN: usize = 1024;
+
+    #[derive(Default)]
+    struct Channel {
+        open: bool,
+        inbox: VecDeque<Vec<u8>>,
+        outbox: Vec<String>,
+        buffer: Vec<u8>,
+    }
+
+    thread_local! {
+        static CHANNEL: RefCell<Channel> = RefCell::new(Channel::default());
+    }
+
+    /// Queues `msg` as the next message from the host.
+    pub fn push(msg: &[u8]) {
+        CHANNEL.with(|channel| channel.borrow_mut().inbox.push_back(msg.to_vec()));
+    }
+
+    /// Gets every message written to the host so far.
+    pub fn sent() -> Vec<String> {
+        CHANNEL.with(|channel| channel.borrow().outbox.clone())
+    }
+
+    pub unsafe fn init_comms() -> i32 {
+        CHANNEL.with(|channel| channel.borrow_mut().open = true);
+        1
+    }
+
+    /// Mirrors the C layer, copying at most [MAX_LEN] bytes of the next message into a NUL
+    /// terminated buffer which stays valid until the next call.
+    pub unsafe fn read_comms() -> *const c_char {
+        CHANNEL.with(|channel| {
+            let mut channel = channel.borrow_mut();
+            if !channel.open {
+                return std::ptr::null();
+            }
+
+            let mut msg = channel.inbox.pop_front().unwrap_or_default();
+            msg.truncate(MAX_LEN);
+            msg.resize(MAX_LEN + 1, 0);
+            channel.buffer = msg;
+            channel.buffer.as_ptr() as *const c_char
+        })
+    }
+
+    pub unsafe fn comms_max_len() -> usize {
+        MAX_LEN
+    }
+
+    pub unsafe fn write_comms(str: *const c_char) -> i32 {
+        let msg = CStr::from_ptr(str).to_string_lossy().into_owned();
+        CHANNEL.with(|channel| channel.borrow_mut().outbox.push(msg));
+        1
+    }
+
+    pub unsafe fn set_comms_timeout(_ms: i32) {}
+
+    pub unsafe fn close_comms() {
+        CHANNEL.with(|channel| channel.borrow_mut().open = false);
+    }
+}
+
+/// Writes `msg` to `writer`, prefixed by its length as a 4 byte big endian integer.
+fn write_frame(mut writer: impl Write, msg: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(msg.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(msg)?;
+    writer.flush()
+}
+
+/// Reads the rest of a message written by [write_frame] from `reader`, once the `first`
+/// byte of its length has been read. A message longer than `limit` is skipped, so the next
+/// message is still found, and rejected with [GVMError::MessageTooLarge].
+fn read_frame(mut reader: impl Read, first: u8, limit: usize) -> Result<Vec<u8>, GVMError> {
+    let eof = |err: io::Error| match err.kind() {
+        io::ErrorKind::UnexpectedEof => GVMError::CommsClosed,
+        _ => GVMError::IOError,
+    };
+
+    let mut len = [first, 0, 0, 0];
+    reader.read_exact(&mut len[1..]).map_err(eof)?;
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > limit {
+        let skipped = io::copy(&mut reader.by_ref().take(len as u64), &mut io::sink())?;
+        if skipped < len as u64 {
+            return Err(GVMError::CommsClosed);
+        }
+        return Err(GVMError::MessageTooLarge(len));
+    }
+
+    let mut msg = vec![0; len];
+    reader.read_exact(&mut msg).map_err(eof)?;
+    Ok(msg)
+}
+
+/// Reads the next message from the `socket`, an empty one if the read timeout passes before
+/// the host sends anything. Reads with a timeout aren't restarted after a signal, so they
+/// also return an empty message when interrupted, ie. by SIGHUP.
+fn read_socket(mut socket: &Socket) -> Result<Vec<u8>, GVMError> {
+    let mut first = [0];
+    match socket.read(&mut first) {
+        Ok(0) => return Err(GVMError::CommsClosed),
+        Ok(_) => {}
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+            ) =>
+        {
+            return Ok(Vec::new())
+        }
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+            ) =>
+        {
+            return Err(GVMError::CommsClosed)
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    // Once a message started, the rest of it is waited for, rather than losing track of
+    // where the next message starts.
+    let timeout = socket.read_timeout()?;
+    socket.set_read_timeout(None)?;
+    let msg = read_frame(socket, first[0], read_limit());
+    socket.set_read_timeout(timeout)?;
+    msg
+}
+
+/// Provides the longest message exchanged with the host.
+fn max_len() -> usize {
+    match socket() {
+        Some(_) => SOCKET_MAX_LEN,
+        None => unsafe { comms_max_len() },
+    }
+}
+
+/// Provides the longest message accepted from the host, the configured limit capped by the
+/// limit of the transport.
+pub fn read_limit() -> usize {
+    match MAX_MESSAGE.load(Ordering::Relaxed) {
+        0 => max_len(),
+        limit => limit.min(max_len()),
+    }
+}
+
+/// Connects to the host over TCP at `addr`, in the form of host:port.
+#[cfg(feature = "tcp")]
+fn connect_tcp(addr: &str) -> Result<Socket, GVMError> {
+    let stream = TcpStream::connect(addr).inspect_err(|err| {
+        error!("Failed to connect to {}: {}", addr, err);
+    })?;
+    // Messages are written whole, there is nothing to gain from delaying them.
+    stream.set_nodelay(true)?;
+    Ok(Socket::Tcp(stream))
+}
+
+/// Refuses comms over TCP, left out of this build.
+#[cfg(not(feature = "tcp"))]
+fn connect_tcp(addr: &str) -> Result<Socket, GVMError> {
+    error!("Comms over TCP to {} need the tcp feature", addr);
+    Err(GVMError::InvalidConfig)
+}
+
+/// Initializes the host -> guest communication line, through the unix `socket` or the TCP
+/// endpoint `tcp` when given instead of the host communication device.
+pub fn init_communications(socket: Option<&Path>, tcp: Option<&str>) -> Result<(), GVMError> {
+    let stream = match (socket, tcp) {
+        (Some(_), Some(_)) => {
+            error!("Only one of comms_socket and comms_tcp can be set");
+            return Err(GVMError::InvalidConfig);
+        }
+        (Some(path), None) => {
+            let stream = UnixStream::connect(path).inspect_err(|err| {
+                error!("Failed to connect to {}: {}", path.display(), err);
+            })?;
+            info!("Communicating with the host through {}", path.display());
+            Some(Socket::Unix(stream))
+        }
+        (None, Some(addr)) => {
+            let stream = connect_tcp(addr)?;
+            info!("Communicating with the host over TCP through {}", addr);
+            Some(stream)
+        }
+        (None, None) => None,
+    };
+    if let Some(stream) = stream {
+        *SOCKET.write().unwrap_or_else(|err| err.into_inner()) = Some(Arc::new(stream));
         return Ok(());
+    }
+
+    if unsafe { init_comms() } == 1 {
+        Ok(())
     } else {
-        return Err(GVMError::IOError);
+        Err(GVMError::IOError)
+    }
+}
+
+/// Provides the socket replacing the C layer, if any.
+fn socket() -> Option<Arc<Socket>> {
+    SOCKET.read().unwrap_or_else(|err| err.into_inner()).clone()
+}
+
+/// Closes the communication line and initializes it again, through the same transport as
+/// [init_communications]. Compression has to be negotiated again, the host may have
+/// restarted.
+pub fn reconnect(socket: Option<&Path>, tcp: Option<&str>) -> Result<(), GVMError> {
+    let closed = SOCKET.write().unwrap_or_else(|err| err.into_inner()).take();
+    if closed.is_none() {
+        unsafe { close_comms() };
+    }
+    #[cfg(feature = "compression")]
+    COMPRESS.store(false, Ordering::Relaxed);
+
+    init_communications(socket, tcp)
+}
+
+/// Requires every message exchanged with the host to be authenticated with `key`. The key
+/// can only be set once, changing it requires restarting the guest program.
+pub fn set_auth_key(key: &str) {
+    if AUTH_KEY.set(key.as_bytes().to_vec()).is_err() {
+        warn!("Authentication key already set, ignoring new key");
     }
 }
 
+/// Sets how many seconds [read_string] waits for the host before returning an empty string,
+/// `None` waits forever.
+pub fn set_read_timeout(secs: Option<u64>) {
+    if let Some(socket) = socket() {
+        let timeout = secs.filter(|secs| *secs > 0).map(Duration::from_secs);
+        if let Err(err) = socket.set_read_timeout(timeout) {
+            warn!("Failed to set the read timeout: {}", err);
+        }
+        return;
+    }
+
+    let ms = match secs {
+        Some(secs) => i32::try_from(secs.saturating_mul(1000)).unwrap_or(i32::MAX),
+        None => -1,
+    };
+    unsafe { set_comms_timeout(ms) };
+}
+
+/// Sets the longest message accepted from the host to `bytes`, `None` only keeps the limit of
+/// the transport.
+pub fn set_max_message(bytes: Option<usize>) {
+    MAX_MESSAGE.store(bytes.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Compression algorithms of the messages sent to the host supported by this build, in
+/// order of preference.
+pub const COMPRESSION_ALGORITHMS: &[&str] = if cfg!(feature = "compression") {
+    &["gzip"]
+} else {
+    &[]
+};
+
+/// Picks the compression algorithm of the messages sent to the host among the `offered` ones,
+/// see [COMPRESSION_ALGORITHMS]. Returns the picked algorithm, `None` keeps sending
+/// plaintext.
+pub fn negotiate_compression(offered: &[String]) -> Option<&'static str> {
+    let picked = COMPRESSION_ALGORITHMS
+        .iter()
+        .copied()
+        .find(|algorithm| offered.iter().any(|offer| offer == algorithm));
+    #[cfg(feature = "compression")]
+    COMPRESS.store(picked.is_some(), Ordering::Relaxed);
+    picked
+}
+
+/// Wraps `s` into a compressed message once the host agreed to it, see [wrap_compressed].
+#[cfg(feature = "compression")]
+fn compress(s: String) -> Result<String, GVMError> {
+    if !COMPRESS.load(Ordering::Relaxed) {
+        return Ok(s);
+    }
+    wrap_compressed(s)
+}
+
+/// Wraps `s` into a compressed message if `s` is long enough and compressing it actually
+/// makes it shorter.
+#[cfg(feature = "compression")]
+fn wrap_compressed(s: String) -> Result<String, GVMError> {
+    if s.len() <= COMPRESS_THRESHOLD {
+        return Ok(s);
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(s.as_bytes())
+        .map_err(|_| GVMError::IOError)?;
+    let gzip = encoder.finish().map_err(|_| GVMError::IOError)?;
+    let wrapped = serde_json::to_string(&Envelope::Compressed(STANDARD.encode(gzip)))
+        .map_err(|err| GVMError::Serialization(err.to_string()))?;
+    Ok(if wrapped.len() < s.len() { wrapped } else { s })
+}
+
+/// Unwraps the compressed message `s`, other messages are returned as is. Compressed
+/// messages are accepted whether or not compression was negotiated.
+#[cfg(feature = "compression")]
+fn decompress(s: String) -> Result<String, GVMError> {
+    if !s.contains("\"Compressed\"") {
+        return Ok(s);
+    }
+    let Ok(Envelope::Compressed(payload)) = serde_json::from_str(&s) else {
+        return Ok(s);
+    };
+
+    let gzip = STANDARD
+        .decode(payload)
+        .map_err(|_| GVMError::InvalidMessage)?;
+    let mut msg = Vec::new();
+    GzDecoder::new(gzip.as_slice())
+        .take(DECOMPRESSED_MAX_LEN as u64 + 1)
+        .read_to_end(&mut msg)
+        .map_err(|_| GVMError::InvalidMessage)?;
+    if msg.len() > DECOMPRESSED_MAX_LEN {
+        return Err(GVMError::MessageTooLarge(msg.len()));
+    }
+    String::from_utf8(msg).map_err(|_| GVMError::InvalidMessage)
+}
+
 /// Reads a string from the host and passes it to the main program.
+///
+/// When an authentication key is set, messages which fail authentication are rejected with
+/// [GVMError::AuthFailed]. Messages longer than [set_max_message] allows, or filling the
+/// whole buffer of the C layer and so possibly truncated, are rejected with
+/// [GVMError::MessageTooLarge]. Messages which aren't UTF-8 are rejected with
+/// [GVMError::InvalidMessage], as are compressed messages which fail to decompress.
+/// Reading before the communication line is initialized, or once the unix socket is
+/// closed, fails with [GVMError::CommsClosed].
 pub fn read_string() -> Result<String, GVMError> {
+    let str_buf = match socket() {
+        Some(socket) => {
+            String::from_utf8(read_socket(&socket)?).map_err(|_| GVMError::InvalidMessage)?
+        }
+        None => read_device()?,
+    };
+
+    let str_buf = match AUTH_KEY.get() {
+        Some(key) if !str_buf.is_empty() => auth::open(key, &str_buf)?,
+        _ => str_buf,
+    };
+    #[cfg(feature = "compression")]
+    let str_buf = decompress(str_buf)?;
+    Ok(str_buf)
+}
+
+/// Reads a string from the host communication device of the C layer.
+fn read_device() -> Result<String, GVMError> {
     let c_buf: *const c_char = unsafe { read_comms() };
+    if c_buf.is_null() {
+        return Err(GVMError::CommsClosed);
+    }
+
+    // The static buffer is only borrowed until it is copied into `str_buf` below, the C
+    // layer is not touched in between.
     let c_str: &CStr = unsafe { CStr::from_ptr(c_buf) };
-    let str_slice: &str = c_str.to_str().unwrap();
-    let str_buf: String = str_slice.to_owned();
-    Ok(str_buf)
+    let len = c_str.to_bytes().len();
+    let max_len = unsafe { comms_max_len() };
+    if len >= max_len {
+        warn!("Message from host reached the {} byte limit", max_len);
+        return Err(GVMError::MessageTooLarge(len));
+    }
+    if len > read_limit() {
+        return Err(GVMError::MessageTooLarge(len));
+    }
+    let str_slice: &str = c_str.to_str().map_err(|_| GVMError::InvalidMessage)?;
+    Ok(str_slice.to_owned())
 }
 
-/// Converts a `cmd` into a command and than passes it into the host.
-pub fn write_command(cmd: Command) -> Result<(), GVMError> {
-    let s: String = serde_json::to_string(&cmd).unwrap().to_owned();
-    let cs = CString::new(s).expect("CString::new failed");
+/// Encodes `msg` into the message sent to the host.
+///
+/// NUL characters inside strings are escaped by serde_json, so none reach the C layer where
+/// they would truncate the message. Long messages are compressed before being
+/// authenticated, once the host agreed to it.
+fn encode(msg: &GuestToHost) -> Result<CString, GVMError> {
+    let mut s: String =
+        serde_json::to_string(msg).map_err(|err| GVMError::Serialization(err.to_string()))?;
+    #[cfg(feature = "compression")]
+    {
+        s = compress(s)?;
+    }
+    if let Some(key) = AUTH_KEY.get() {
+        s = auth::seal(key, s)?;
+    }
+    CString::new(s).map_err(|_| GVMError::InvalidMessage)
+}
+
+/// Checks if `msg` fits in a single message, the host reads messages into a buffer of the
+/// same size as the guest.
+pub fn fits_message(msg: &GuestToHost) -> bool {
+    match encode(msg) {
+        Ok(cs) => cs.as_bytes().len() < max_len(),
+        Err(_) => false,
+    }
+}
+
+/// Converts a `msg` into a command and than passes it into the host. A `msg` which can't be
+/// serialized fails with [GVMError::Serialization] before anything is written.
+pub fn write_command(msg: &GuestToHost) -> Result<(), GVMError> {
+    let cs = encode(msg)?;
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(socket) = socket() {
+        return write_frame(&*socket, cs.as_bytes()).map_err(|_| GVMError::IOError);
+    }
     if unsafe { write_comms(cs.as_ptr()) } == 1 {
-        return Ok(());
+        Ok(())
     } else {
-        return Err(GVMError::IOError);
+        Err(GVMError::IOError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Command, GVMCmd};
+
+    fn reply(resp: &str) -> GuestToHost {
+        GuestToHost::Reply(Command {
+            cmd: GVMCmd::Ping,
+            resp: Some(resp.to_owned()),
+            data: None,
+            finished: Some(true),
+            id: None,
+            seq: None,
+            sequence: None,
+        })
+    }
+
+    #[test]
+    fn read_before_init_is_closed() {
+        assert!(matches!(read_string(), Err(GVMError::CommsClosed)));
+    }
+
+    #[test]
+    fn reconnect_reopens_closed_line() {
+        init_communications(None, None).unwrap();
+        unsafe { close_comms() };
+        assert!(matches!(read_string(), Err(GVMError::CommsClosed)));
+
+        reconnect(None, None).unwrap();
+        stub::push(b"{}");
+        assert_eq!(read_string().unwrap(), "{}");
+    }
+
+    #[test]
+    fn read_returns_queued_message() {
+        init_communications(None, None).unwrap();
+        stub::push(br#"{"type":"Network","payload":[]}"#);
+
+        assert_eq!(read_string().unwrap(), r#"{"type":"Network","payload":[]}"#);
+        assert_eq!(read_string().unwrap(), "");
+    }
+
+    #[test]
+    fn read_rejects_invalid_utf8() {
+        init_communications(None, None).unwrap();
+        stub::push(&[b'{', 0xff, b'}']);
+
+        assert!(matches!(read_string(), Err(GVMError::InvalidMessage)));
+    }
+
+    #[test]
+    fn read_rejects_truncated_message() {
+        init_communications(None, None).unwrap();
+        stub::push(&[b'a'; 2048]);
+
+        assert!(matches!(read_string(), Err(GVMError::MessageTooLarge(_))));
+    }
+
+    #[test]
+    fn write_sends_json() {
+        init_communications(None, None).unwrap();
+        write_command(&reply("pong")).unwrap();
+
+        let sent = stub::sent();
+        assert_eq!(sent.len(), 1);
+        let value: serde_json::Value = serde_json::from_str(&sent[0]).unwrap();
+        assert_eq!(value["type"], "Reply");
+        assert_eq!(value["payload"]["cmd"], "Ping");
+        assert_eq!(value["payload"]["resp"], "pong");
+        assert!(value["payload"].get("seq").is_none());
+    }
+
+    #[test]
+    fn write_escapes_nul() {
+        init_communications(None, None).unwrap();
+        write_command(&reply("a\0b")).unwrap();
+
+        assert!(stub::sent()[0].contains(r#""resp":"a\u0000b""#));
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let (mut guest, host) = UnixStream::pair().unwrap();
+        write_frame(&host, b"{}").unwrap();
+
+        let mut first = [0];
+        guest.read_exact(&mut first).unwrap();
+        assert_eq!(read_frame(&guest, first[0], SOCKET_MAX_LEN).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn frame_too_long() {
+        let (mut guest, host) = UnixStream::pair().unwrap();
+        write_frame(&host, b"[1, 2, 3]").unwrap();
+        write_frame(&host, b"{}").unwrap();
+
+        let mut first = [0];
+        guest.read_exact(&mut first).unwrap();
+        let err = read_frame(&guest, first[0], 4).unwrap_err();
+        assert!(matches!(err, GVMError::MessageTooLarge(9)));
+
+        // The long message is skipped whole, the next one is read as usual.
+        guest.read_exact(&mut first).unwrap();
+        assert_eq!(read_frame(&guest, first[0], 4).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn long_message_does_not_fit() {
+        assert!(fits_message(&reply("pong")));
+        assert!(!fits_message(&reply(&"a".repeat(2048))));
+    }
+
+    #[cfg(feature = "compression")]
+    fn gzip_envelope(msg: &[u8]) -> String {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(msg).unwrap();
+        serde_json::to_string(&Envelope::Compressed(
+            STANDARD.encode(encoder.finish().unwrap()),
+        ))
+        .unwrap()
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compression_round_trip() {
+        let long = serde_json::to_string(&reply(&"a".repeat(4096))).unwrap();
+        let wrapped = wrap_compressed(long.clone()).unwrap();
+        assert!(wrapped.len() < long.len());
+        assert!(wrapped.contains("\"Compressed\""));
+        assert_eq!(decompress(wrapped).unwrap(), long);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn short_message_not_compressed() {
+        let short = serde_json::to_string(&reply("pong")).unwrap();
+        assert_eq!(wrap_compressed(short.clone()).unwrap(), short);
+        assert_eq!(decompress(short.clone()).unwrap(), short);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn decompress_rejects_oversized_message() {
+        let wrapped = gzip_envelope(&vec![b'a'; DECOMPRESSED_MAX_LEN + 1]);
+        assert!(matches!(
+            decompress(wrapped),
+            Err(GVMError::MessageTooLarge(len)) if len > DECOMPRESSED_MAX_LEN
+        ));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn decompress_rejects_invalid_payload() {
+        let not_base64 = r#"{"type":"Compressed","payload":"@@@"}"#.to_owned();
+        assert!(matches!(
+            decompress(not_base64),
+            Err(GVMError::InvalidMessage)
+        ));
+
+        let not_gzip = serde_json::to_string(&Envelope::Compressed(STANDARD.encode("{}"))).unwrap();
+        assert!(matches!(
+            decompress(not_gzip),
+            Err(GVMError::InvalidMessage)
+        ));
+
+        assert!(matches!(
+            decompress(gzip_envelope(&[b'{', 0xff, b'}'])),
+            Err(GVMError::InvalidMessage)
+        ));
     }
 }
@@ -0,0 +1,247 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This streams the new lines of guest logs to the host, to debug provisioning failures.
+//!
+//! Only the logs inside the configured allowlist can be streamed, either files followed
+//! through `tail` or the systemd journal followed through `journalctl`, named `journal` in
+//! the allowlist. Every stream runs on its own thread, sending each line as a progress
+//! command until the host stops it, the log ends, or the configured volume is reached. The
+//! stream then sends a final reply with the reason it ended.
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, ChildStdout, Command as Process, Stdio};
+use std::result::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::common::{Command, GVMCmd, GVMError, GuestToHost};
+use crate::config::Config;
+
+/// Name of the systemd journal inside the allowlist.
+const JOURNAL: &str = "journal";
+
+/// Longest line sent to the host, longer lines are cut so they fit in a single message.
+const MAX_LINE: usize = 512;
+
+/// Log being streamed to the host.
+struct Stream {
+    /// Process following the log.
+    child: Arc<Mutex<Child>>,
+    /// Set when the host stops the stream.
+    stopped: Arc<AtomicBool>,
+    /// Thread sending the lines of the log.
+    thread: JoinHandle<()>,
+}
+
+impl Stream {
+    /// Stops following the log, the thread then sends the final reply.
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Ok(mut child) = self.child.lock() {
+            let _ = child.kill();
+        }
+    }
+}
+
+/// Host command which started a stream, echoed back in everything the stream sends.
+struct Origin {
+    /// Correlation ID of the command.
+    id: Option<String>,
+    /// Sequence number of the command.
+    sequence: Option<u64>,
+}
+
+/// Limits of a stream, taken from the configuration when it starts.
+struct Limits {
+    /// Lines sent per second.
+    rate: u32,
+    /// Bytes sent in total.
+    volume: u64,
+}
+
+/// Keeps track of the logs streamed to the host, at most one stream per log.
+pub struct LogStreams {
+    streams: HashMap<String, Stream>,
+}
+
+/// Cuts `line` to at most [MAX_LINE] bytes, without splitting any character.
+fn truncate(mut line: String) -> String {
+    if line.len() > MAX_LINE {
+        let mut end = MAX_LINE;
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        line.truncate(end);
+    }
+    line
+}
+
+/// Sends the lines read from `stdout` of the `child` following `path` to the host through
+/// `send`, tagged with the `origin` command which started the stream. Stops once the child
+/// exits, or the volume of `limits` is reached.
+fn follow(
+    path: String,
+    origin: Origin,
+    stdout: ChildStdout,
+    child: Arc<Mutex<Child>>,
+    stopped: Arc<AtomicBool>,
+    limits: Limits,
+    send: fn(GuestToHost),
+) {
+    let mut sent: u64 = 0;
+    let mut window = Instant::now();
+    let mut lines: u32 = 0;
+    let mut reason = "Ended";
+
+    for line in BufReader::new(stdout).lines() {
+        let Ok(line) = line else { break };
+        let line = truncate(line);
+
+        sent += line.len() as u64;
+        if sent > limits.volume {
+            info!(
+                "Log {} reached {} bytes, stopping stream",
+                path, limits.volume
+            );
+            reason = "LimitReached";
+            break;
+        }
+
+        // Lines beyond the rate wait for the next second, the log backs up in the pipe.
+        if lines >= limits.rate {
+            let elapsed = window.elapsed();
+            if elapsed < Duration::from_secs(1) {
+                thread::sleep(Duration::from_secs(1) - elapsed);
+            }
+            window = Instant::now();
+            lines = 0;
+        }
+        lines += 1;
+
+        send(GuestToHost::Progress(Command {
+            cmd: GVMCmd::StreamLog,
+            resp: Some(line),
+            data: None,
+            finished: None,
+            id: origin.id.clone(),
+            seq: None,
+            sequence: origin.sequence,
+        }));
+    }
+
+    if let Ok(mut child) = child.lock() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    if stopped.load(Ordering::Relaxed) {
+        reason = "Stopped";
+    }
+
+    send(GuestToHost::Reply(Command {
+        cmd: GVMCmd::StreamLog,
+        resp: Some(reason.to_owned()),
+        data: None,
+        finished: Some(true),
+        id: origin.id,
+        seq: None,
+        sequence: origin.sequence,
+    }));
+}
+
+impl LogStreams {
+    pub fn new() -> LogStreams {
+        LogStreams {
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Starts streaming the new lines of the log at `path` to the host through `send`, tagged
+    /// with the `id` and `sequence` of the command starting it. The log has to be inside the
+    /// allowlist of `config`, and not already streamed.
+    pub fn start(
+        &mut self,
+        path: &str,
+        id: Option<String>,
+        sequence: Option<u64>,
+        config: &Config,
+        send: fn(GuestToHost),
+    ) -> Result<(), GVMError> {
+        if !config.log_allowlist.iter().any(|allowed| allowed == path) {
+            warn!("Refusing to stream {}, not in the allowlist", path);
+            return Err(GVMError::LogNotAllowed(path.to_owned()));
+        }
+
+        self.streams
+            .retain(|_, stream| !stream.thread.is_finished());
+        if self.streams.contains_key(path) {
+            return Err(GVMError::StreamActive(path.to_owned()));
+        }
+
+        let mut process = if path == JOURNAL {
+            let mut process = Process::new("journalctl");
+            process.args(["--follow", "--lines=0"]);
+            process
+        } else {
+            let mut process = Process::new("tail");
+            process.args(["--follow=name", "--retry", "--lines=0", path]);
+            process
+        };
+        let mut child = process
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let stdout = child.stdout.take().ok_or(GVMError::IOError)?;
+        let child = Arc::new(Mutex::new(child));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let limits = Limits {
+            rate: config.log_stream_rate.max(1),
+            volume: config.log_stream_limit,
+        };
+
+        let thread = {
+            let origin = Origin { id, sequence };
+            let path = path.to_owned();
+            let child = Arc::clone(&child);
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || follow(path, origin, stdout, child, stopped, limits, send))
+        };
+
+        info!("Streaming {}", path);
+        self.streams.insert(
+            path.to_owned(),
+            Stream {
+                child,
+                stopped,
+                thread,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Stops streaming the log at `path`, the stream sends its final reply once stopped.
+    /// Returns `false` if the log was not streamed.
+    pub fn stop(&mut self, path: &str) -> bool {
+        match self.streams.remove(path) {
+            Some(stream) => {
+                info!("Stopping stream of {}", path);
+                stream.stop();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops every stream, waiting for them to send their final reply.
+    pub fn stop_all(&mut self) {
+        for (path, stream) in self.streams.drain() {
+            info!("Stopping stream of {}", path);
+            stream.stop();
+            let _ = stream.thread.join();
+        }
+    }
+}
@@ -3,10 +3,32 @@
 //! This is the linux specific component of GVM guest programs.
 //!
 //! 1. init_net - Implemented inside the networking module, and supports both systemd and
-//!               netplan backed networking stacks.
+//!    netplan backed networking stacks.
 //! 2. init_communications - This is implemented inside the comms module, and uses a mutable
-//!                          C module.
+//!    C module.
 //! 3. read_string, write_command - These are implemented inside the comms module and uses
-//!                                 a mutable C module.
+//!    a mutable C module.
+//!
+//! System wide actions such as powering off the guest live inside the system module, while
+//! the environment checks run for troubleshooting live inside the selftest module. Guest
+//! logs are streamed to the host from the logstream module.
+use std::sync::atomic::{AtomicBool, Ordering};
+
 pub mod comms;
+pub mod logstream;
 pub mod networking;
+pub mod selftest;
+pub mod system;
+
+/// Set when the guest program runs with --dry-run.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Makes every change to the system be logged instead of applied when `dry_run` is set.
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+/// Checks if changes to the system are only logged, see [set_dry_run].
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
@@ -0,0 +1,115 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This writes the network configuration for several backends at once, for hybrid images
+//! where the backend driving the interfaces after a reboot isn't known, ie. netplan or
+//! systemd-networkd depending on a kernel flag.
+//!
+//! The configuration of every backend is written, but only the active backend, the one
+//! found by [super::detect_backend], applies it. The extra backends listed in
+//! [Config::extra_backends] never run their apply command.
+use super::{remove_file, Renderer};
+use crate::common::{Bond, GVMError, Network, WireGuard};
+use crate::config::Config;
+use std::net::IpAddr;
+use std::result::Result;
+
+/// Renders through the `active` backend and every `extra` backend.
+pub struct Hybrid {
+    /// Renderer of the active backend, the only one applying the configuration.
+    pub active: Box<dyn Renderer>,
+    /// Renderers of the extra backends, only writing the configuration.
+    pub extra: Vec<Box<dyn Renderer>>,
+}
+
+impl Hybrid {
+    /// Runs `write` on the active renderer, then on every extra renderer.
+    fn each(
+        &mut self,
+        mut write: impl FnMut(&mut dyn Renderer) -> Result<(), GVMError>,
+    ) -> Result<(), GVMError> {
+        write(self.active.as_mut())?;
+        for renderer in &mut self.extra {
+            write(renderer.as_mut())?;
+        }
+        Ok(())
+    }
+}
+
+impl Renderer for Hybrid {
+    fn nic(&mut self, net: &Network, nic: &str, config: &Config) -> Result<(), GVMError> {
+        self.each(|renderer| renderer.nic(net, nic, config))
+    }
+
+    fn bond(
+        &mut self,
+        net: &Network,
+        bond: &Bond,
+        nics: &[String],
+        config: &Config,
+    ) -> Result<(), GVMError> {
+        self.each(|renderer| renderer.bond(net, bond, nics, config))
+    }
+
+    fn wireguard(&mut self, net: &Network, wg: &WireGuard) -> Result<(), GVMError> {
+        self.each(|renderer| renderer.wireguard(net, wg))
+    }
+
+    fn loopback(&mut self, addrs: &[IpAddr]) -> Result<(), GVMError> {
+        self.each(|renderer| renderer.loopback(addrs))
+    }
+
+    fn apply(&self, config: &Config, links: &[String]) -> Result<(), GVMError> {
+        self.active.apply(config, links)
+    }
+
+    fn reconcile(&self, nic: &str, mac: &str) -> Result<(), GVMError> {
+        self.active.reconcile(nic, mac)?;
+        for renderer in &self.extra {
+            renderer.reconcile(nic, mac)?;
+        }
+        Ok(())
+    }
+
+    // The files of the extra backends are removed without applying anything, the active
+    // backend re-applies once its own files are gone.
+    fn remove(&self, mac: &str, config: &Config) -> Result<bool, GVMError> {
+        let active = self.active.files(mac)?;
+        let mut removed = false;
+        for renderer in &self.extra {
+            for file in renderer.files(mac)? {
+                if active.contains(&file) {
+                    continue;
+                }
+                info!("Removing {}", file);
+                remove_file(file)?;
+                removed = true;
+            }
+        }
+
+        Ok(self.active.remove(mac, config)? || removed)
+    }
+
+    fn files(&self, mac: &str) -> Result<Vec<String>, GVMError> {
+        let mut files = self.active.files(mac)?;
+        for renderer in &self.extra {
+            for file in renderer.files(mac)? {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn owned(&self) -> Result<Vec<String>, GVMError> {
+        let mut files = self.active.owned()?;
+        for renderer in &self.extra {
+            for file in renderer.owned()? {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        Ok(files)
+    }
+}
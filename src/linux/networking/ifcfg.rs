@@ -0,0 +1,452 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This configures networking through RHEL style ifcfg files inside
+//! /etc/sysconfig/network-scripts, WireGuard tunnels are brought up through wg-quick.
+use super::{
+    dns_servers, matching_files, policy_routes, remove_file, remove_if_exists, run_privileged,
+    split_address, wg_quick_enable, wg_quick_networking, wg_quick_owned, write_config, Renderer,
+    OWNED_MARK,
+};
+use crate::common::{Addressing, Bond, GVMError, LinkSettings, Network, WireGuard};
+use crate::config::Config;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::result::Result;
+use uuid::Uuid;
+
+/// Provides the ifcfg addressing, gateway and DNS configuration of `net`, shared by all
+/// interface kinds carrying an address.
+fn ifcfg_addressing(net: &Network, config: &Config) -> Result<String, GVMError> {
+    let (gateway, cidr) = split_address(net)?;
+
+    let privacy = match net.ipv6_privacy {
+        Some(true) => "IPV6_PRIVACY=rfc3041\n",
+        Some(false) => "IPV6_PRIVACY=no\n",
+        None => "",
+    };
+
+    // The network scripts read METRIC, NetworkManager reads IPV4_ROUTE_METRIC.
+    let metric = match net.metric {
+        Some(metric) => format!("METRIC={metric}\nIPV4_ROUTE_METRIC={metric}\n"),
+        None => "".to_owned(),
+    };
+
+    if net.addressing == Addressing::DhcpFallback {
+        let mut ret = "".to_owned() + "BOOTPROTO=dhcp\n" + privacy + &metric;
+        if !net.configures_dns() {
+            ret += "PEERDNS=no\n";
+        }
+        return Ok(ret + "IPV4_FAILURE_FATAL=no\n");
+    }
+
+    // Magic algorithm for CIDR calculation, don't touch now.
+    let netmask_og: u32 = ((((1_u64) << 32_u64) - 1) as u32) << (32 - cidr);
+    let netmask_1: u32 = netmask_og & 0x000000FF;
+    let netmask_2: u32 = (netmask_og & 0x0000FF00) >> 8;
+    let netmask_3: u32 = (netmask_og & 0x00FF0000) >> 16;
+    let netmask_4: u32 = (netmask_og & 0xFF000000) >> 24;
+    let netmask = format!("{}.{}.{}.{}", netmask_4, netmask_3, netmask_2, netmask_1);
+
+    let mut dns: String = dns_servers(net, config)
+        .iter()
+        .enumerate()
+        .map(|(i, server)| format!("DNS{}={}\n", i + 1, server))
+        .collect();
+    if !net.configures_dns() {
+        dns += "PEERDNS=no\n";
+    }
+
+    let mut ret = "".to_owned() + "BOOTPROTO=none\n" + privacy + &metric;
+
+    // Without a gateway the NIC sits on a flat layer 2 segment, and must not take over the
+    // default route. Inside a VRF the default route lives in the VRF table, see [Ifcfg].
+    match gateway.filter(|_| net.vrf.is_none()) {
+        Some(gateway) => ret = ret + "DEFROUTE=yes\n" + "GATEWAY=" + &gateway + "\n",
+        None => ret += "DEFROUTE=no\n",
+    }
+
+    ret = ret
+        + "NETMASK="
+        + &netmask
+        + "\n"
+        + &dns
+        + "IPADDR="
+        + &net.address()?.to_string()
+        + "\n"
+        + "IPV4_FAILURE_FATAL=no\n";
+
+    Ok(ret)
+}
+
+/// Provides the UUID of the existing ifcfg file `file_name`, if it has a valid one.
+fn ifcfg_uuid(file_name: &str) -> Option<Uuid> {
+    let contents = fs::read_to_string(file_name).ok()?;
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("UUID="))
+        .and_then(|uuid| Uuid::parse_str(uuid.trim().trim_matches('"')).ok())
+}
+
+/// Provides the ethtool options pinning the link `settings`, passed to ethtool by the network
+/// scripts as the interface comes up.
+fn ifcfg_ethtool(settings: Option<&LinkSettings>) -> String {
+    let settings = match settings {
+        Some(settings) => settings,
+        None => return "".to_owned(),
+    };
+
+    let mut opts = Vec::new();
+    if let Some(speed) = settings.speed {
+        opts.push("speed ".to_owned() + &speed.to_string());
+    }
+    if let Some(duplex) = &settings.duplex {
+        opts.push("duplex ".to_owned() + duplex);
+    }
+    if let Some(autoneg) = settings.autoneg {
+        opts.push("autoneg ".to_owned() + if autoneg { "on" } else { "off" });
+    }
+
+    "".to_owned() + "ETHTOOL_OPTS=\"" + &opts.join(" ") + "\"\n"
+}
+
+/// Writes the ifcfg file of `nic`, `body` holds the type and addressing configuration. The
+/// UUID of an existing file is kept, so rewriting it doesn't turn it into a new connection.
+fn ifcfg_write(nic: &str, body: &str) -> Result<(), GVMError> {
+    let file_name = "/etc/sysconfig/network-scripts/".to_owned() + "ifcfg-" + nic;
+    let uuid = ifcfg_uuid(&file_name).unwrap_or_else(Uuid::new_v4);
+
+    info!("Using nic: {} -> {}", nic, uuid);
+
+    let contents = "".to_owned()
+        + OWNED_MARK
+        + "\n"
+        + body
+        + "NAME="
+        + nic
+        + "\n"
+        + "UUID="
+        + &uuid.to_string()
+        + "\n"
+        + "DEVICE="
+        + nic
+        + "\n"
+        + "ONBOOT=yes\n"
+        + "IPV6INIT=no";
+
+    write_config(&file_name, &contents, 0o644)
+}
+
+/// Prefix of the loopback alias devices written by the guest, the alias number follows.
+const LOOPBACK_ALIAS: &str = "lo:gvm";
+
+/// Writes the route and rule files of `nic` holding the routing policies of `net`, which the
+/// network scripts pass to ip route and ip rule as the interface comes up. Files left from a
+/// previous configuration are removed when `net` has no routing policy.
+fn ifcfg_policy(net: &Network, nic: &str) -> Result<(), GVMError> {
+    let mut routes = "".to_owned();
+    let mut rules = "".to_owned();
+
+    for (policy, via) in policy_routes(net)? {
+        let table = policy.table.to_string();
+
+        routes = routes
+            + &policy.to.map_or("default".to_owned(), |to| to.to_string())
+            + " via "
+            + &via
+            + " table "
+            + &table
+            + "\n";
+
+        if let Some(from) = &policy.from {
+            rules = rules + "from " + &from.to_string() + " ";
+        }
+        if let Some(to) = &policy.to {
+            rules = rules + "to " + &to.to_string() + " ";
+        }
+        rules = rules + "table " + &table + "\n";
+    }
+
+    for (kind, contents) in [("route-", routes), ("rule-", rules)] {
+        let file_name = "/etc/sysconfig/network-scripts/".to_owned() + kind + nic;
+        if contents.is_empty() {
+            remove_if_exists(&file_name)?;
+        } else {
+            write_config(&file_name, &contents, 0o644)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// This function configures the specific NIC network script inside
+/// /etc/sysconfig/network-scripts to handle systemd networking control
+/// correctly for a given `net`, found as `nic`.
+fn systemd_networking(net: &Network, nic: &str, config: &Config) -> Result<(), GVMError> {
+    let mut body = "".to_owned();
+
+    if !net.mac.is_empty() {
+        body = body + "HWADDR=" + &net.mac + "\n";
+    }
+    body = body
+        + "TYPE=Ethernet\n"
+        + &ifcfg_ethtool(net.link.as_ref())
+        + &ifcfg_addressing(net, config)?;
+
+    ifcfg_write(nic, &body)?;
+    ifcfg_policy(net, nic)
+}
+
+/// This function configures the network scripts of the `bond` described by `net`, with its
+/// members found as `nics`. The bond carries the address, while the members are enslaved to
+/// it without any addressing of their own.
+fn systemd_bond(
+    net: &Network,
+    bond: &Bond,
+    nics: &[String],
+    config: &Config,
+) -> Result<(), GVMError> {
+    for (nic, mac) in nics.iter().zip(&bond.members) {
+        let body = "".to_owned()
+            + "HWADDR="
+            + mac
+            + "\n"
+            + "TYPE=Ethernet\n"
+            + &ifcfg_ethtool(net.link.as_ref())
+            + "BOOTPROTO=none\n"
+            + "MASTER="
+            + &bond.name
+            + "\n"
+            + "SLAVE=yes\n";
+        ifcfg_write(nic, &body)?;
+    }
+
+    let body = "".to_owned()
+        + "TYPE=Bond\n"
+        + "BONDING_MASTER=yes\n"
+        + "BONDING_OPTS=\"mode="
+        + &bond.mode
+        + " miimon=100\"\n"
+        + &ifcfg_addressing(net, config)?;
+
+    ifcfg_write(&bond.name, &body)?;
+    ifcfg_policy(net, &bond.name)
+}
+
+/// Searches /etc/sysconfig/network-scripts for ifcfg files with the `mac` hardware address.
+fn ifcfg_files(mac: &str) -> Result<Vec<String>, GVMError> {
+    let needles = ["HWADDR=".to_owned() + mac];
+    matching_files(
+        "/etc/sysconfig/network-scripts",
+        |name| name.starts_with("ifcfg-"),
+        &needles,
+    )
+}
+
+/// Adds the route and rule files sitting next to the ifcfg `files` of their interface.
+fn with_policy(files: Vec<String>) -> Vec<String> {
+    let mut ret = Vec::new();
+
+    for file in files {
+        let policy: Vec<String> = ["route-", "rule-"]
+            .iter()
+            .map(|kind| file.replacen("/ifcfg-", &("/".to_owned() + kind), 1))
+            .filter(|policy| Path::new(policy).exists())
+            .collect();
+        ret.push(file);
+        ret.extend(policy);
+    }
+
+    ret
+}
+
+/// Interface enslaved to a VRF once the network service restarted, the network scripts
+/// know nothing about VRFs.
+struct Vrf {
+    name: String,
+    table: u32,
+    iface: String,
+    gateway: Option<String>,
+    metric: Option<u32>,
+}
+
+impl Vrf {
+    /// Provides the VRF of `net` found as `iface`, if any.
+    fn new(net: &Network, iface: &str) -> Result<Option<Vrf>, GVMError> {
+        let Some((name, table)) = net.vrf()? else {
+            return Ok(None);
+        };
+        let (gateway, _) = split_address(net)?;
+
+        Ok(Some(Vrf {
+            name: name.to_owned(),
+            table,
+            iface: iface.to_owned(),
+            gateway,
+            metric: net.metric,
+        }))
+    }
+
+    /// Creates the VRF, enslaves the interface to it and routes the default route of the VRF
+    /// table through the gateway. Creating a VRF which already exists fails harmlessly.
+    fn enslave(&self, config: &Config) -> Result<(), GVMError> {
+        let table = self.table.to_string();
+        let name = self.name.as_str();
+
+        run_privileged(
+            config,
+            &["ip", "link", "add", name, "type", "vrf", "table", &table],
+        )?;
+        run_privileged(config, &["ip", "link", "set", name, "up"])?;
+        run_privileged(config, &["ip", "link", "set", &self.iface, "master", name])?;
+
+        if let Some(gateway) = &self.gateway {
+            let mut args = vec![
+                "ip", "route", "replace", "default", "via", gateway, "table", &table,
+            ];
+            let metric = self.metric.map(|metric| metric.to_string());
+            if let Some(metric) = &metric {
+                args.extend(["metric", metric]);
+            }
+            run_privileged(config, &args)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Renders the network configuration as ifcfg files, restarted through the network
+/// service. `wg_quick` holds the tunnels to enable once applied, and `vrfs` the interfaces
+/// to enslave to their VRF.
+#[derive(Default)]
+pub struct Ifcfg {
+    wg_quick: Vec<String>,
+    vrfs: Vec<Vrf>,
+}
+
+impl Renderer for Ifcfg {
+    fn nic(&mut self, net: &Network, nic: &str, config: &Config) -> Result<(), GVMError> {
+        systemd_networking(net, nic, config)?;
+        self.vrfs.extend(Vrf::new(net, nic)?);
+        Ok(())
+    }
+
+    fn bond(
+        &mut self,
+        net: &Network,
+        bond: &Bond,
+        nics: &[String],
+        config: &Config,
+    ) -> Result<(), GVMError> {
+        systemd_bond(net, bond, nics, config)?;
+        self.vrfs.extend(Vrf::new(net, &bond.name)?);
+        Ok(())
+    }
+
+    fn wireguard(&mut self, net: &Network, wg: &WireGuard) -> Result<(), GVMError> {
+        wg_quick_networking(net, wg)?;
+        self.wg_quick.push(wg.name.clone());
+        Ok(())
+    }
+
+    fn loopback(&mut self, addrs: &[IpAddr]) -> Result<(), GVMError> {
+        let dir = "/etc/sysconfig/network-scripts/";
+
+        // The network scripts only bring up IPv4 aliases.
+        if let Some(addr) = addrs.iter().find(|addr| addr.is_ipv6()) {
+            warn!("IPv6 loopback aliases are not supported with ifcfg files");
+            return Err(GVMError::InvalidPrefix(addr.to_string()));
+        }
+
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with(&("ifcfg-".to_owned() + LOOPBACK_ALIAS)) {
+                remove_file(dir.to_owned() + &name)?;
+            }
+        }
+
+        for (i, addr) in addrs.iter().enumerate() {
+            let device = LOOPBACK_ALIAS.to_owned() + &i.to_string();
+            let contents = "".to_owned()
+                + OWNED_MARK
+                + "\n"
+                + "DEVICE="
+                + &device
+                + "\n"
+                + "IPADDR="
+                + &addr.to_string()
+                + "\n"
+                + "PREFIX=32\n"
+                + "ONBOOT=yes\n";
+            write_config(&(dir.to_owned() + "ifcfg-" + &device), &contents, 0o644)?;
+        }
+
+        Ok(())
+    }
+
+    fn apply(&self, config: &Config, _links: &[String]) -> Result<(), GVMError> {
+        run_privileged(config, &["systemctl", "restart", "network"])?;
+        for vrf in &self.vrfs {
+            vrf.enslave(config)?;
+        }
+        wg_quick_enable(config, &self.wg_quick)
+    }
+
+    fn reconcile(&self, nic: &str, mac: &str) -> Result<(), GVMError> {
+        let mut needles = Vec::new();
+        if !mac.is_empty() {
+            needles.push("HWADDR=".to_owned() + mac);
+        }
+        needles.push("DEVICE=".to_owned() + nic);
+
+        let own = "ifcfg-".to_owned() + nic;
+        let files = matching_files(
+            "/etc/sysconfig/network-scripts",
+            |name| name.starts_with("ifcfg-") && name != own,
+            &needles,
+        )?;
+
+        for file in files {
+            info!("Removing conflicting {}", file);
+            remove_file(file)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, mac: &str, config: &Config) -> Result<bool, GVMError> {
+        let files = ifcfg_files(mac)?;
+        if files.is_empty() {
+            return Ok(false);
+        }
+
+        for file in files {
+            info!("Removing {}", file);
+            remove_file(&file)?;
+
+            // The route and rule files of the interface sit next to its ifcfg file.
+            for kind in ["route-", "rule-"] {
+                remove_if_exists(&file.replacen("/ifcfg-", &("/".to_owned() + kind), 1))?;
+            }
+        }
+
+        run_privileged(config, &["systemctl", "restart", "network"])?;
+        Ok(true)
+    }
+
+    fn files(&self, mac: &str) -> Result<Vec<String>, GVMError> {
+        Ok(with_policy(ifcfg_files(mac)?))
+    }
+
+    fn owned(&self) -> Result<Vec<String>, GVMError> {
+        let marked = matching_files(
+            "/etc/sysconfig/network-scripts",
+            |name| name.starts_with("ifcfg-"),
+            &[OWNED_MARK.to_owned()],
+        )?;
+        let mut files = with_policy(marked);
+        files.extend(wg_quick_owned()?);
+
+        Ok(files)
+    }
+}
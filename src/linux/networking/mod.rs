@@ -0,0 +1,1713 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This code is specific to the networking NIC section of the VM.
+//!
+//! The procedure for adding in a networking NIC is as follows:
+//!
+//! 1. Find corresponding networking device associated with the passed in interface name or
+//!    MAC address.
+//! 2. Determine if we are on a netplan, ifcfg or systemd-networkd backed system.
+//! 3. Create backend specific configurations, netplan gets one file per interface.
+//! 4. Apply changes for backend specifically.
+//!
+//! Bonds resolve every member MAC address in the first step, the bond interface carries the
+//! address while its members are enslaved to it.
+//!
+//! WireGuard tunnels skip the first step, and are provisioned as netplan tunnels or through
+//! wg-quick on ifcfg and systemd-networkd backed systems.
+//!
+//! Every decision taken along the way is also logged as a structured event, see [event], so
+//! log pipelines can follow the provisioning of every NIC.
+//!
+//! Every backend lives inside its own module behind the cargo feature of the same name, and
+//! implements [Renderer]. Systems running a backend left out of the build are refused with
+//! [GVMError::UnsupportedBackend], so a build without any backend never configures the
+//! network. Hybrid images can have the configuration of extra
+//! backends written along with the active one, see [hybrid].
+#[cfg(any(feature = "netplan", feature = "networkd"))]
+use crate::common::LinkSettings;
+use crate::common::{Addressing, Bond, Dns, GVMError, LinkState, Network, RoutePolicy, WireGuard};
+use crate::config::{Config, InterfaceMatch};
+use crate::files::write_atomic;
+use crate::linux::dry_run;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+use std::result::Result;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod hybrid;
+#[cfg(feature = "ifcfg")]
+mod ifcfg;
+#[cfg(feature = "netplan")]
+mod netplan;
+#[cfg(feature = "networkd")]
+mod networkd;
+
+/// Networking backends the guest knows how to configure, named in lowercase inside the guest
+/// configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum Backend {
+    /// Netplan YAML inside /etc/netplan.
+    Netplan,
+    /// RHEL style ifcfg files inside /etc/sysconfig/network-scripts.
+    Ifcfg,
+    /// systemd-networkd .network and .netdev files inside /etc/systemd/network.
+    Networkd,
+}
+
+impl Backend {
+    /// Every backend the guest knows of, built in or not.
+    pub const ALL: [Backend; 3] = [Backend::Netplan, Backend::Ifcfg, Backend::Networkd];
+
+    /// Checks if support for the backend was built in.
+    pub fn built(self) -> bool {
+        match self {
+            Backend::Netplan => cfg!(feature = "netplan"),
+            Backend::Ifcfg => cfg!(feature = "ifcfg"),
+            Backend::Networkd => cfg!(feature = "networkd"),
+        }
+    }
+}
+
+/// Logs the structured provisioning event `name` as `event=<name> key=value ...`, with
+/// `fields` as the keys and values. Empty values, and values holding spaces, quotes or `=`,
+/// are quoted.
+fn event(name: &str, fields: &[(&str, &str)]) {
+    let mut line = "event=".to_owned() + name;
+
+    for (key, value) in fields {
+        line = line + " " + key + "=";
+        if value.is_empty() || value.contains([' ', '"', '=']) {
+            line += &format!("{:?}", value);
+        } else {
+            line += value;
+        }
+    }
+
+    info!("{}", line);
+}
+
+/// Writes the configuration file `path` with `contents` and the permissions `mode`, see
+/// [write_atomic], creating its directory if needed.
+fn write_config(path: &str, contents: &str, mode: u32) -> Result<(), GVMError> {
+    // The contents aren't logged, they may hold keys.
+    if dry_run() {
+        info!("Would write {}", path);
+        return Ok(());
+    }
+
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(Path::new(path), contents, mode)?;
+    event("file_written", &[("path", path)]);
+    Ok(())
+}
+
+/// Removes the configuration file `path`.
+fn remove_file(path: impl AsRef<Path>) -> io::Result<()> {
+    if dry_run() {
+        info!("Would remove {}", path.as_ref().display());
+        return Ok(());
+    }
+
+    fs::remove_file(path)
+}
+
+/// Lists the files inside `dir` whose name passes `filter`, none if `dir` doesn't exist.
+fn listed_files(dir: &str, filter: impl Fn(&str) -> bool) -> Result<Vec<String>, GVMError> {
+    if !Path::new(dir).is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let passes = path
+            .file_name()
+            .is_some_and(|name| filter(&name.to_string_lossy()));
+        if passes && path.is_file() {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+/// First line of the files written by the guest which can't be told apart by their name,
+/// ie. ifcfg files named after their interface.
+const OWNED_MARK: &str = "# Written by gvm-guest";
+
+/// Removes `file_name`, succeeding if it doesn't exist.
+#[cfg_attr(
+    not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+    allow(dead_code)
+)]
+fn remove_if_exists(file_name: &str) -> Result<(), GVMError> {
+    match remove_file(file_name) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// MAC address -> names of every networking device inside the guest with that address.
+type MacMap = HashMap<String, Vec<String>>;
+
+/// This function iterates through the /sys/class/net devices once, and reads the address
+/// field for every device. The result maps each (lowercase) MAC address to the names of its
+/// devices, so resolving many NICs doesn't rescan sysfs for every one of them.
+///
+/// Every device is only logged at the debug level, guests with many interfaces would
+/// otherwise flood the logs with their MAC addresses on every scan.
+fn scan_macs() -> Result<MacMap, GVMError> {
+    let started = Instant::now();
+    let start_dir = "/sys/class/net/";
+    let mut macs = MacMap::new();
+
+    for entry in fs::read_dir(start_dir)? {
+        let entry = entry?;
+        let path: String = entry.file_name().to_string_lossy().into_owned();
+
+        let prev_contents = match fs::read_to_string(start_dir.to_owned() + &path + "/address") {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        let contents = prev_contents.strip_suffix("\n").unwrap_or(&prev_contents);
+
+        debug!("NIC: {}, MAC: {}", path, contents);
+
+        macs.entry(contents.to_lowercase()).or_default().push(path);
+    }
+
+    for names in macs.values_mut() {
+        names.sort();
+    }
+    debug!(
+        "Scanned {} MAC addresses in {} ms",
+        macs.len(),
+        started.elapsed().as_millis()
+    );
+
+    Ok(macs)
+}
+
+/// Looks up the name of the device with the `mac` address inside `macs`. A MAC shared by
+/// several devices, ie. mirrored by a bridge or veth pair, returns
+/// [GVMError::AmbiguousMac] rather than configuring an arbitrary one of them.
+fn find_mac(macs: &MacMap, mac: &str) -> Result<String, GVMError> {
+    match macs.get(&mac.to_lowercase()).map(|names| names.as_slice()) {
+        Some([name]) => Ok(name.clone()),
+        Some(names) if !names.is_empty() => {
+            warn!("MAC {} is shared by {:?}", mac, names);
+            Err(GVMError::AmbiguousMac(names.to_vec()))
+        }
+        _ => Err(GVMError::NicNotFound(mac.to_owned())),
+    }
+}
+
+/// Provides the PCI address of the device behind `nic`. Devices such as virtio NICs sit
+/// below their PCI device, so the last PCI address on the path to the device is used.
+fn pci_slot(nic: &str) -> Option<String> {
+    let device = fs::canonicalize(Path::new("/sys/class/net").join(nic).join("device")).ok()?;
+
+    device
+        .iter()
+        .rev()
+        .filter_map(|component| component.to_str())
+        .find(|component| {
+            let parts: Vec<&str> = component.split([':', '.']).collect();
+            parts.len() == 4
+                && parts
+                    .iter()
+                    .all(|part| u32::from_str_radix(part, 16).is_ok())
+        })
+        .map(|slot| slot.to_lowercase())
+}
+
+/// Looks up the name of the NIC at the PCI address `pci`, the PCI domain defaults to 0000.
+fn find_pci(pci: &str) -> Result<String, GVMError> {
+    let mut slot = pci.to_lowercase();
+    if slot.matches(':').count() == 1 {
+        slot = "0000:".to_owned() + &slot;
+    }
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir("/sys/class/net")? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if pci_slot(&name).as_deref() == Some(slot.as_str()) {
+            names.push(name);
+        }
+    }
+    names.sort();
+
+    match names.as_slice() {
+        [name] => Ok(name.clone()),
+        [] => Err(GVMError::NicNotFound(pci.to_owned())),
+        _ => {
+            warn!("PCI address {} is shared by {:?}", pci, names);
+            Err(GVMError::AmbiguousMac(names))
+        }
+    }
+}
+
+/// Finds the NIC of `net` through its interface name, MAC address or PCI address, trying
+/// the selectors it carries in the `order` of the guest configuration. A selector which
+/// finds no NIC falls back to the next one, while one matching several NICs is an error.
+fn resolve_interface(
+    macs: &MacMap,
+    net: &Network,
+    order: &[InterfaceMatch],
+) -> Result<String, GVMError> {
+    let started = Instant::now();
+    let mut missing = None;
+
+    for strategy in order {
+        let found = match strategy {
+            InterfaceMatch::Name => net.iface.as_ref().map(|iface| {
+                if Path::new("/sys/class/net").join(iface).exists() {
+                    Ok(iface.clone())
+                } else {
+                    Err(GVMError::NicNotFound(iface.clone()))
+                }
+            }),
+            InterfaceMatch::Mac => (!net.mac.is_empty()).then(|| find_mac(macs, &net.mac)),
+            InterfaceMatch::Pci => net.pci.as_deref().map(find_pci),
+        };
+
+        match found {
+            Some(Err(GVMError::NicNotFound(selector))) => {
+                warn!("NIC {} not found", selector);
+                missing = Some(selector);
+            }
+            Some(Ok(nic)) => {
+                let selector = match strategy {
+                    InterfaceMatch::Name => net.iface.as_deref().unwrap_or_default(),
+                    InterfaceMatch::Mac => &net.mac,
+                    InterfaceMatch::Pci => net.pci.as_deref().unwrap_or_default(),
+                };
+                info!(
+                    "Resolved {} {} to {} in {} ms",
+                    format!("{:?}", strategy).to_lowercase(),
+                    selector,
+                    nic,
+                    started.elapsed().as_millis()
+                );
+                return Ok(nic);
+            }
+            Some(res) => return res,
+            None => continue,
+        }
+    }
+
+    Err(GVMError::NicNotFound(
+        missing.unwrap_or_else(|| net.mac.to_string()),
+    ))
+}
+
+/// Provides the address of `net` as written in the network, for reporting.
+fn address_text(net: &Network) -> String {
+    net.ip.map(|ip| ip.to_string()).unwrap_or_default()
+}
+
+/// Provides the gateway of `net` and the cidr of its address, taken from `prefix`. When
+/// `prefix` is absent, the cidr comes from the address, or from a gateway still in the
+/// legacy gateway-ip/cidr form. NICs on a flat layer 2 segment have no gateway, and get no
+/// default route.
+fn split_address(net: &Network) -> Result<(Option<String>, u32), GVMError> {
+    let gateway = net.gateway.map(|gateway| gateway.addr.to_string());
+    let legacy = net.gateway.and_then(|gateway| gateway.cidr);
+
+    match net.prefix.or(net.ip.and_then(|ip| ip.cidr)).or(legacy) {
+        Some(prefix) if prefix > 0 && prefix <= 32 => Ok((gateway, prefix)),
+        _ => Err(GVMError::InvalidPrefix(address_text(net))),
+    }
+}
+
+/// Provides the DNS servers configured for `net`, none if it must leave the resolver
+/// configuration of the system untouched.
+#[cfg_attr(
+    not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+    allow(dead_code)
+)]
+fn dns_servers<'a>(net: &Network, config: &'a Config) -> &'a [String] {
+    if net.configures_dns() {
+        &config.dns_servers
+    } else {
+        &[]
+    }
+}
+
+/// Provides the prefix length of a single host with the address `ip`, ie. 32 or 128.
+fn host_prefix(ip: &IpAddr) -> u32 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Validates the loopback alias `net` is a host address, returning its IP.
+fn loopback_address(net: &Network) -> Result<IpAddr, GVMError> {
+    let ip = net
+        .address()
+        .map_err(|_| GVMError::InvalidPrefix(address_text(net)))?;
+
+    let prefix = net.prefix.or(net.ip.and_then(|ip| ip.cidr));
+    if prefix.is_some_and(|prefix| prefix != host_prefix(&ip)) {
+        return Err(GVMError::InvalidPrefix(address_text(net)));
+    }
+
+    Ok(ip)
+}
+
+/// Validates the address of `net` is an IPv4 address, reached through an IPv4 gateway with
+/// a valid prefix. Loopback aliases only have to be host addresses, see [loopback_address],
+/// and NICs only changing their gateway an IPv4 gateway.
+fn validate_address(net: &Network) -> Result<(), GVMError> {
+    if net.loopback {
+        return loopback_address(net).map(|_| ());
+    }
+    if net.gateway_only() {
+        return gateway_only(net).map(|_| ());
+    }
+
+    if !net.address()?.is_ipv4() {
+        return Err(GVMError::InvalidAddress(address_text(net)));
+    }
+
+    split_address(net)?;
+    match net.gateway {
+        Some(gateway) if !gateway.addr.is_ipv4() => {
+            Err(GVMError::InvalidGateway(gateway.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Provides the IPv4 gateway of a NIC only changing its gateway, the cidr of the legacy
+/// gateway-ip/cidr form is ignored as the address is left alone.
+fn gateway_only(net: &Network) -> Result<String, GVMError> {
+    match net.gateway {
+        Some(gateway) if gateway.addr.is_ipv4() => Ok(gateway.addr.to_string()),
+        gateway => Err(GVMError::InvalidGateway(
+            gateway
+                .map(|gateway| gateway.to_string())
+                .unwrap_or_default(),
+        )),
+    }
+}
+
+/// Validates the routing policies of `net`, pairing each of them with the gateway its table
+/// routes through, the gateway of the policy or otherwise the gateway of `net`. NICs trying
+/// DHCP get no routing policy until they fall back to their static address.
+#[cfg_attr(
+    not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+    allow(dead_code)
+)]
+fn policy_routes(net: &Network) -> Result<Vec<(&RoutePolicy, String)>, GVMError> {
+    let (gateway, _) = split_address(net)?;
+    let mut routes = Vec::new();
+
+    for policy in &net.routing_policy {
+        policy.validate()?;
+        let via = policy
+            .via
+            .map(|via| via.to_string())
+            .or_else(|| gateway.clone())
+            .ok_or_else(|| GVMError::InvalidRoutePolicy(policy.table.to_string()))?;
+        routes.push((policy, via));
+    }
+
+    if net.addressing == Addressing::DhcpFallback {
+        routes.clear();
+    }
+
+    Ok(routes)
+}
+
+/// This function writes the wg-quick configuration for the WireGuard tunnel `wg` described
+/// by `net` inside /etc/wireguard. The tunnel is brought up by the wg-quick service.
+#[cfg(any(feature = "ifcfg", feature = "networkd"))]
+fn wg_quick_networking(net: &Network, wg: &WireGuard) -> Result<(), GVMError> {
+    let (_, cidr) = split_address(net)?;
+    let file_name = "/etc/wireguard/".to_owned() + &wg.name + ".conf";
+
+    let mut contents = "".to_owned()
+        + OWNED_MARK
+        + "\n"
+        + "[Interface]\n"
+        + "PrivateKey = "
+        + &wg.private_key
+        + "\n"
+        + "Address = "
+        + &net.address()?.to_string()
+        + "/"
+        + &cidr.to_string()
+        + "\n";
+
+    if let Some(port) = wg.listen_port {
+        contents = contents + "ListenPort = " + &port.to_string() + "\n";
+    }
+
+    contents = contents + "\n[Peer]\n" + "PublicKey = " + &wg.peer_public_key + "\n";
+
+    if let Some(endpoint) = &wg.endpoint {
+        contents = contents + "Endpoint = " + endpoint + "\n";
+    }
+
+    contents = contents + "AllowedIPs = " + &wg.allowed_ips.join(", ") + "\n";
+
+    write_config(&file_name, &contents, 0o600)?;
+
+    Ok(())
+}
+
+/// Searches /etc/wireguard for the wg-quick configuration written by the guest.
+#[cfg(any(feature = "ifcfg", feature = "networkd"))]
+fn wg_quick_owned() -> Result<Vec<String>, GVMError> {
+    if !Path::new("/etc/wireguard").is_dir() {
+        return Ok(Vec::new());
+    }
+
+    matching_files(
+        "/etc/wireguard",
+        |name| name.ends_with(".conf"),
+        &[OWNED_MARK.to_owned()],
+    )
+}
+
+/// Enables and restarts the wg-quick service of every tunnel in `names`.
+#[cfg(any(feature = "ifcfg", feature = "networkd"))]
+fn wg_quick_enable(config: &Config, names: &[String]) -> Result<(), GVMError> {
+    for name in names {
+        let unit = "wg-quick@".to_owned() + name;
+        run_privileged(config, &["systemctl", "enable", &unit])?;
+        run_privileged(config, &["systemctl", "restart", &unit])?;
+    }
+
+    Ok(())
+}
+
+/// Writes and applies the configuration of a single networking backend.
+trait Renderer {
+    /// Writes the configuration of the NIC described by `net`, found as `nic`.
+    fn nic(&mut self, net: &Network, nic: &str, config: &Config) -> Result<(), GVMError>;
+    /// Writes the configuration of the `bond` described by `net`, with its members found as
+    /// `nics`.
+    fn bond(
+        &mut self,
+        net: &Network,
+        bond: &Bond,
+        nics: &[String],
+        config: &Config,
+    ) -> Result<(), GVMError>;
+    /// Writes the configuration of the WireGuard tunnel `wg` described by `net`.
+    fn wireguard(&mut self, net: &Network, wg: &WireGuard) -> Result<(), GVMError>;
+    /// Writes the loopback aliases `addrs`, replacing the ones previously written.
+    fn loopback(&mut self, addrs: &[IpAddr]) -> Result<(), GVMError>;
+    /// Applies everything written so far, `links` are the NICs and bonds configured.
+    fn apply(&self, config: &Config, links: &[String]) -> Result<(), GVMError>;
+    /// Removes configuration of the NIC with the `mac` address, found as `nic`, which was
+    /// not written by the guest, ie. baked into the image. The configuration written by the
+    /// guest then authoritatively replaces it, rather than stacking a second definition of
+    /// the same interface on top of it.
+    fn reconcile(&self, nic: &str, mac: &str) -> Result<(), GVMError>;
+    /// Removes the configuration written for the NIC with the `mac` address and re-applies
+    /// it, returning `false` without touching the system if none exists.
+    fn remove(&self, mac: &str, config: &Config) -> Result<bool, GVMError>;
+    /// Lists the files written for the NIC with the `mac` address.
+    fn files(&self, mac: &str) -> Result<Vec<String>, GVMError>;
+    /// Lists every file written by the guest, leaving out the ones of the distribution.
+    fn owned(&self) -> Result<Vec<String>, GVMError>;
+}
+
+/// Provides the renderer of `backend`, or [GVMError::UnsupportedBackend] if it was left out
+/// of the build.
+fn renderer(backend: Backend) -> Result<Box<dyn Renderer>, GVMError> {
+    match backend {
+        #[cfg(feature = "netplan")]
+        Backend::Netplan => Ok(Box::new(netplan::Netplan)),
+        #[cfg(feature = "ifcfg")]
+        Backend::Ifcfg => Ok(Box::<ifcfg::Ifcfg>::default()),
+        #[cfg(feature = "networkd")]
+        Backend::Networkd => Ok(Box::<networkd::Networkd>::default()),
+        #[allow(unreachable_patterns)]
+        backend => {
+            warn!("Support for {:?} was not built in", backend);
+            Err(GVMError::UnsupportedBackend(format!("{:?}", backend)))
+        }
+    }
+}
+
+/// Provides the renderer of the active `backend`, also writing the configuration of the
+/// extra backends of the guest `config`. Extra backends without their configuration
+/// directory are skipped, creating it could change the backend detected on the next run.
+fn configured_renderer(backend: Backend, config: &Config) -> Result<Box<dyn Renderer>, GVMError> {
+    let mut extra = Vec::new();
+    for &other in &config.extra_backends {
+        if other == backend {
+            continue;
+        }
+        if !Path::new(backend_dir(other)).is_dir() {
+            warn!("Skipping {:?}, {} is missing", other, backend_dir(other));
+            continue;
+        }
+        extra.push(renderer(other)?);
+    }
+
+    if extra.is_empty() {
+        return renderer(backend);
+    }
+    Ok(Box::new(hybrid::Hybrid {
+        active: renderer(backend)?,
+        extra,
+    }))
+}
+
+/// Directory holding the systemd-networkd configuration.
+const NETWORKD_DIR: &str = "/etc/systemd/network";
+
+/// Prefix of the systemd .link files written by the guest.
+#[cfg(any(feature = "netplan", feature = "networkd"))]
+const LINK_PREFIX: &str = "10-gvm-";
+
+/// Writes the systemd .link file pinning the link `settings` of `nic`, found with the `mac`
+/// address, and has udev apply it right away. Without `settings` any .link file left from a
+/// previous configuration is removed, leaving the link parameters alone from the next boot.
+///
+/// The first .link file matching a NIC replaces the default one along with its naming
+/// policy, so the file also pins the current name of `nic`.
+#[cfg(any(feature = "netplan", feature = "networkd"))]
+fn write_link(
+    nic: &str,
+    mac: &str,
+    settings: Option<&LinkSettings>,
+    config: &Config,
+) -> Result<(), GVMError> {
+    let file_name = NETWORKD_DIR.to_owned() + "/" + LINK_PREFIX + nic + ".link";
+    let settings = match settings {
+        Some(settings) => settings,
+        None => return remove_if_exists(&file_name),
+    };
+
+    let mac = if mac.is_empty() {
+        fs::read_to_string("/sys/class/net/".to_owned() + nic + "/address")?
+            .trim()
+            .to_owned()
+    } else {
+        mac.to_lowercase()
+    };
+
+    let mut contents = "".to_owned()
+        + "[Match]\n"
+        + "MACAddress="
+        + &mac
+        + "\n"
+        + "\n"
+        + "[Link]\n"
+        + "Name="
+        + nic
+        + "\n";
+    if let Some(speed) = settings.speed {
+        contents = contents + "BitsPerSecond=" + &speed.to_string() + "M\n";
+    }
+    if let Some(duplex) = &settings.duplex {
+        contents = contents + "Duplex=" + duplex + "\n";
+    }
+    if let Some(autoneg) = settings.autoneg {
+        contents = contents + "AutoNegotiation=" + if autoneg { "yes" } else { "no" } + "\n";
+    }
+
+    write_config(&file_name, &contents, 0o644)?;
+    let device = "/sys/class/net/".to_owned() + nic;
+    run_privileged(config, &["udevadm", "trigger", "--action=add", &device])
+}
+
+/// Checks if the systemd unit `name` is currently active.
+pub fn service_active(name: &str) -> bool {
+    Command::new("systemctl")
+        .args(["is-active", "--quiet", name])
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Hands the DNS servers and search domains of the configuration to systemd-resolved for
+/// every one of `links`, when it runs. Not every backend passes the DNS servers of its files
+/// on to systemd-resolved, which then ignores them. The settings only last until the link
+/// is reconfigured, so the files keep carrying the DNS servers.
+fn resolved_dns(links: &[String], config: &Config) -> Result<(), GVMError> {
+    if links.is_empty() || !service_active("systemd-resolved") {
+        return Ok(());
+    }
+
+    for link in links {
+        let mut args = vec!["resolvectl", "dns", link.as_str()];
+        args.extend(config.dns_servers.iter().map(|server| server.as_str()));
+        run_privileged(config, &args)?;
+
+        if !config.dns_search.is_empty() {
+            let mut args = vec!["resolvectl", "domain", link.as_str()];
+            args.extend(config.dns_search.iter().map(|domain| domain.as_str()));
+            run_privileged(config, &args)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the DNSSEC and DNS-over-TLS modes of `net` on `iface` through systemd-resolved. Only
+/// systemd-networkd files carry the modes, so on the other backends they only last until
+/// the link is reconfigured. Without systemd-resolved the modes can't be enforced, and the
+/// NIC fails rather than silently resolving without them.
+fn resolved_dns_security(net: &Network, iface: &str, config: &Config) -> Result<(), GVMError> {
+    if !dry_run() && !service_active("systemd-resolved") {
+        warn!("DNSSEC and DNS-over-TLS of {} need systemd-resolved", iface);
+        return Err(GVMError::InvalidDns(iface.to_owned()));
+    }
+
+    let yes_no = |enabled: bool| if enabled { "yes" } else { "no" };
+    for (setting, mode) in [("dnssec", net.dnssec), ("dnsovertls", net.dns_over_tls)] {
+        if let Some(mode) = mode {
+            run_privileged_checked(config, &["resolvectl", setting, iface, yes_no(mode)])?;
+            event(
+                "dns_security_set",
+                &[
+                    ("iface", iface),
+                    ("setting", setting),
+                    ("mode", yes_no(mode)),
+                ],
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Directory holding the MACsec configuration of wpa_supplicant, gone on reboot like the
+/// MACsec interfaces themselves.
+const MACSEC_DIR: &str = "/run/gvm-guest/macsec";
+
+/// Provides the transient systemd unit running MKA for `iface`.
+fn macsec_unit(iface: &str) -> String {
+    "gvm-macsec-".to_owned() + iface
+}
+
+/// Creates [MACSEC_DIR], only accessible by root as it holds keys, tightening its
+/// permissions if it already exists.
+fn macsec_dir() -> Result<(), GVMError> {
+    if let Some(parent) = Path::new(MACSEC_DIR).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match DirBuilder::new().mode(0o700).create(MACSEC_DIR) {
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            fs::set_permissions(MACSEC_DIR, fs::Permissions::from_mode(0o700))?;
+        }
+        res => res?,
+    }
+    Ok(())
+}
+
+/// Sets up the MACsec of `net` on `iface`, which has to be up. Neither netplan nor `ip
+/// macsec` take a connectivity association key, so the keys are agreed on with the peer
+/// through the MKA implementation of wpa_supplicant, which creates the MACsec interface on
+/// top of `iface`. It runs as a transient unit, replacing any previous one of `iface`. The
+/// keys only live inside its configuration file, readable by root.
+fn add_macsec(net: &Network, iface: &str, config: &Config) -> Result<(), GVMError> {
+    let Some(macsec) = &net.macsec else {
+        return Ok(());
+    };
+    macsec.validate()?;
+
+    let contents = "".to_owned()
+        + OWNED_MARK
+        + "\n"
+        + "eapol_version=3\n"
+        + "ap_scan=0\n"
+        + "network={\n"
+        + "  key_mgmt=NONE\n"
+        + "  eapol_flags=0\n"
+        + "  macsec_policy=1\n"
+        + "  macsec_integ_only="
+        + if macsec.encrypts() { "0" } else { "1" }
+        + "\n"
+        + "  mka_cak="
+        + &macsec.cak
+        + "\n"
+        + "  mka_ckn="
+        + &macsec.ckn
+        + "\n"
+        + "}\n";
+    let file_name = MACSEC_DIR.to_owned() + "/" + iface + ".conf";
+    if !dry_run() {
+        macsec_dir()?;
+    }
+    write_config(&file_name, &contents, 0o600)?;
+
+    let unit = macsec_unit(iface);
+    // Stopping fails when no previous unit runs, which is fine.
+    run_privileged(config, &["systemctl", "stop", &unit])?;
+    run_privileged_checked(
+        config,
+        &[
+            "systemd-run",
+            "--unit",
+            &unit,
+            "wpa_supplicant",
+            "-i",
+            iface,
+            "-D",
+            "macsec_linux",
+            "-c",
+            &file_name,
+        ],
+    )
+}
+
+/// Sets up the MACsec of `net` on `iface`, see [add_macsec]. The outcome is reported through
+/// `progress`, without failing the NIC.
+fn report_macsec(net: &Network, iface: &str, config: &Config, progress: &mut dyn FnMut(&str)) {
+    match add_macsec(net, iface, config) {
+        Ok(()) => {
+            event("macsec_started", &[("iface", iface)]);
+            progress(&("macsec on ".to_owned() + iface + " started"));
+        }
+        Err(err) => {
+            warn!("Failed to set up MACsec on {}: {:?}", iface, err);
+            event(
+                "macsec_failed",
+                &[("iface", iface), ("reason", &err.to_string())],
+            );
+            progress(&("macsec on ".to_owned() + iface + " failed: " + &err.to_string()));
+        }
+    }
+}
+
+/// Checks if any netplan configuration hands the interfaces to NetworkManager.
+fn netplan_uses_network_manager() -> bool {
+    let entries = match fs::read_dir("/etc/netplan") {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+
+    entries.flatten().any(|entry| {
+        fs::read_to_string(entry.path())
+            .map(|contents| contents.contains("renderer: NetworkManager"))
+            .unwrap_or(false)
+    })
+}
+
+/// Determines which backend is actually driving the network on this system.
+///
+/// When only one of /etc/netplan or /etc/sysconfig/network-scripts is present the choice is
+/// obvious. When neither is present, systemd-networkd is configured directly if it is
+/// running, otherwise ifcfg files are written. On hybrid systems with both, we look at which
+/// renderer is running:
+///
+/// * systemd-networkd is netplan's default renderer.
+/// * NetworkManager renders netplan if netplan is configured to use it, otherwise it reads
+///   the ifcfg files.
+/// * The legacy network service reads the ifcfg files.
+///
+/// If this does not lead to a single backend, [GVMError::AmbiguousBackend] is returned
+/// rather than writing configuration the running renderer would ignore.
+pub fn detect_backend() -> Result<Backend, GVMError> {
+    let netplan = Path::new("/etc/netplan").is_dir();
+    let ifcfg = Path::new("/etc/sysconfig/network-scripts").is_dir();
+
+    if !netplan && !ifcfg && service_active("systemd-networkd") {
+        return Ok(Backend::Networkd);
+    } else if !netplan {
+        return Ok(Backend::Ifcfg);
+    } else if !ifcfg {
+        return Ok(Backend::Netplan);
+    }
+
+    let mut candidates: Vec<Backend> = Vec::new();
+
+    if service_active("systemd-networkd") {
+        candidates.push(Backend::Netplan);
+    }
+    if service_active("NetworkManager") {
+        if netplan_uses_network_manager() {
+            candidates.push(Backend::Netplan);
+        } else {
+            candidates.push(Backend::Ifcfg);
+        }
+    }
+    if service_active("network") {
+        candidates.push(Backend::Ifcfg);
+    }
+
+    candidates.dedup();
+
+    if candidates.len() != 1 {
+        warn!("Unable to determine active renderer: {:?}", candidates);
+        return Err(GVMError::AmbiguousBackend);
+    }
+
+    Ok(candidates[0])
+}
+
+/// Returns the tools used to apply the configuration of `backend`.
+pub fn backend_tools(backend: Backend) -> Vec<&'static str> {
+    match backend {
+        Backend::Netplan if netplan_uses_network_manager() => vec!["netplan", "nmcli"],
+        Backend::Netplan => vec!["netplan", "networkctl"],
+        Backend::Ifcfg => vec!["systemctl"],
+        Backend::Networkd => vec!["networkctl"],
+    }
+}
+
+/// Returns the services which may apply the configuration of `backend`, any of them being
+/// active marks the backend as in use, see [detect_backend].
+pub fn backend_services(backend: Backend) -> Vec<&'static str> {
+    match backend {
+        Backend::Netplan => vec!["systemd-networkd", "NetworkManager"],
+        Backend::Ifcfg => vec!["network", "NetworkManager"],
+        Backend::Networkd => vec!["systemd-networkd"],
+    }
+}
+
+/// Returns the directory the configuration of `backend` is written to.
+pub fn backend_dir(backend: Backend) -> &'static str {
+    match backend {
+        Backend::Netplan => "/etc/netplan",
+        Backend::Ifcfg => "/etc/sysconfig/network-scripts",
+        Backend::Networkd => NETWORKD_DIR,
+    }
+}
+
+/// Runs `args` as a privileged command using the configured privilege command.
+fn run_privileged(config: &Config, args: &[&str]) -> Result<(), GVMError> {
+    if dry_run() {
+        info!("Would run {:?}", args);
+        return Ok(());
+    }
+
+    Command::new(&config.privilege_command)
+        .args(args)
+        .output()?;
+    Ok(())
+}
+
+/// Runs `args` through the privilege command like [run_privileged], failing with
+/// [GVMError::IOError] when the command exits unsuccessfully.
+pub fn run_privileged_checked(config: &Config, args: &[&str]) -> Result<(), GVMError> {
+    if dry_run() {
+        info!("Would run {:?}", args);
+        return Ok(());
+    }
+
+    let output = Command::new(&config.privilege_command)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        warn!(
+            "{:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(GVMError::IOError);
+    }
+
+    Ok(())
+}
+
+/// Searches `dir` for the files whose name passes `filter`, holding any of the `needles`
+/// lines. Lines are compared ignoring case, quotes and surrounding whitespace.
+#[cfg_attr(
+    not(any(feature = "netplan", feature = "ifcfg", feature = "networkd")),
+    allow(dead_code)
+)]
+fn matching_files(
+    dir: &str,
+    filter: impl Fn(&str) -> bool,
+    needles: &[String],
+) -> Result<Vec<String>, GVMError> {
+    let mut files = Vec::new();
+    let needles: Vec<String> = needles.iter().map(|needle| needle.to_lowercase()).collect();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let passes = path
+            .file_name()
+            .is_some_and(|name| filter(&name.to_string_lossy()));
+        if !passes || !path.is_file() {
+            continue;
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        let matches = contents.lines().any(|line| {
+            let line = line.trim().replace('"', "").to_lowercase();
+            needles.contains(&line)
+        });
+        if matches {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Removes the configuration written for the NIC with the `mac` address, and re-applies
+/// networking. This works even if the NIC was already removed from the guest. Returns
+/// `false` without touching the system if no configuration exists for the NIC.
+pub fn remove_net(mac: &str, config: &Config) -> Result<bool, GVMError> {
+    info!("Removing network {}", mac);
+
+    configured_renderer(detect_backend()?, config)?.remove(mac, config)
+}
+
+/// Removes every network configuration file written by the guest, and re-applies
+/// networking, so the system is back to the configuration of its distribution. Files of the
+/// distribution are left alone, even those which were stripped of an interface configured
+/// by the guest. Returns the files removed.
+pub fn reset_net(config: &Config) -> Result<Vec<String>, GVMError> {
+    info!("Resetting network configuration");
+
+    let renderer = configured_renderer(detect_backend()?, config)?;
+    let mut files = renderer.owned()?;
+    if Path::new(RESOLVED_DROP_IN).exists() {
+        files.push(RESOLVED_DROP_IN.to_owned());
+    }
+    files.extend(listed_files(MACSEC_DIR, |name| name.ends_with(".conf"))?);
+
+    for file in &files {
+        if let Some(iface) = file
+            .strip_prefix(&(MACSEC_DIR.to_owned() + "/"))
+            .and_then(|name| name.strip_suffix(".conf"))
+        {
+            run_privileged(config, &["systemctl", "stop", &macsec_unit(iface)])?;
+        }
+
+        // Tunnels of wg-quick keep running from their service, not their file.
+        if let Some(name) = file
+            .strip_prefix("/etc/wireguard/")
+            .and_then(|name| name.strip_suffix(".conf"))
+        {
+            let unit = "wg-quick@".to_owned() + name;
+            run_privileged(config, &["systemctl", "disable", "--now", &unit])?;
+        }
+
+        info!("Removing {}", file);
+        remove_file(file)?;
+    }
+
+    if files.iter().any(|file| file == RESOLVED_DROP_IN) {
+        run_privileged(config, &["systemctl", "restart", "systemd-resolved"])?;
+    }
+    renderer.apply(config, &[])?;
+    event("network_reset", &[("files", &files.len().to_string())]);
+
+    Ok(files)
+}
+
+/// Configuration file written by the guest.
+#[derive(Serialize, Debug)]
+pub struct ConfigFile {
+    /// Absolute path of the file.
+    pub path: String,
+    /// Contents of the file as found on disk.
+    pub contents: String,
+}
+
+/// Configuration written by the guest for a NIC, reported to the host for auditing.
+#[derive(Serialize, Debug)]
+pub struct NicConfig {
+    /// Names of the devices with the MAC address, empty once the NIC left the guest.
+    pub ifaces: Vec<String>,
+    /// Files configuring the NIC.
+    pub files: Vec<ConfigFile>,
+}
+
+/// Provides the configuration files written for the NIC with the `mac` address, or for
+/// every NIC inside the guest without it, keyed by lowercase MAC address. The files of the
+/// extra backends of the guest `config` are included. NICs without any file written by the
+/// guest are left out.
+pub fn network_config(
+    mac: Option<&str>,
+    config: &Config,
+) -> Result<BTreeMap<String, NicConfig>, GVMError> {
+    let renderer = configured_renderer(detect_backend()?, config)?;
+    let scanned = scan_macs()?;
+    let macs: Vec<String> = match mac {
+        Some(mac) => vec![mac.to_lowercase()],
+        None => scanned.keys().cloned().collect(),
+    };
+
+    let mut configs = BTreeMap::new();
+    for mac in macs {
+        let mut files = Vec::new();
+        for path in renderer.files(&mac)? {
+            let contents = fs::read_to_string(&path)?;
+            files.push(ConfigFile { path, contents });
+        }
+        if files.is_empty() {
+            continue;
+        }
+
+        let ifaces = scanned.get(&mac).cloned().unwrap_or_default();
+        configs.insert(mac, NicConfig { ifaces, files });
+    }
+
+    Ok(configs)
+}
+
+/// Drop-in holding the DNS configuration of systemd-resolved.
+const RESOLVED_DROP_IN: &str = "/etc/systemd/resolved.conf.d/90-gvm.conf";
+
+/// Replaces the DNS configuration of the system with `dns`, without touching the addressing
+/// of any interface. Systems running systemd-resolved get a drop-in with the global DNS
+/// configuration, the others get their /etc/resolv.conf rewritten.
+pub fn set_dns(dns: &Dns, config: &Config) -> Result<(), GVMError> {
+    for server in &dns.nameservers {
+        if server.parse::<IpAddr>().is_err() {
+            return Err(GVMError::InvalidDns(server.clone()));
+        }
+    }
+    for domain in &dns.search {
+        let legal = !domain.is_empty()
+            && domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        if !legal {
+            return Err(GVMError::InvalidDns(domain.clone()));
+        }
+    }
+
+    info!("Setting DNS: {:?}", dns);
+
+    if service_active("systemd-resolved") {
+        let contents = "".to_owned()
+            + "[Resolve]\n"
+            + "DNS="
+            + &dns.nameservers.join(" ")
+            + "\n"
+            + "Domains="
+            + &dns.search.join(" ")
+            + "\n";
+
+        write_config(RESOLVED_DROP_IN, &contents, 0o644)?;
+        run_privileged(config, &["systemctl", "restart", "systemd-resolved"])?;
+    } else {
+        let mut contents: String = dns
+            .nameservers
+            .iter()
+            .map(|server| "nameserver ".to_owned() + server + "\n")
+            .collect();
+        if !dns.search.is_empty() {
+            contents = contents + "search " + &dns.search.join(" ") + "\n";
+        }
+
+        write_config("/etc/resolv.conf", &contents, 0o644)?;
+    }
+
+    Ok(())
+}
+
+/// Names the interfaces holding a default route, read from /proc/net/route.
+fn default_route_ifaces() -> Vec<String> {
+    let routes = fs::read_to_string("/proc/net/route").unwrap_or_default();
+
+    // Columns are the interface then the destination, in hex.
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            match (columns.next(), columns.next()) {
+                (Some(iface), Some("00000000")) => Some(iface.to_owned()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Checks if `iface` is administratively up, read from the IFF_UP bit of its flags.
+fn admin_up(iface: &str) -> bool {
+    let flags =
+        fs::read_to_string("/sys/class/net/".to_owned() + iface + "/flags").unwrap_or_default();
+    u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16)
+        .is_ok_and(|flags| flags & 0x1 != 0)
+}
+
+/// Brings the NIC with the `mac` address up or down to `state`, without touching its
+/// configuration. The guest would lose its way out bringing down the interface holding its
+/// default route, so that only happens when `force` is set.
+pub fn set_link_state(
+    mac: &str,
+    state: LinkState,
+    force: bool,
+    config: &Config,
+) -> Result<(), GVMError> {
+    let nic = find_mac(&scan_macs()?, mac)?;
+
+    if state == LinkState::Down && !force && default_route_ifaces().contains(&nic) {
+        warn!("Refusing to bring down {}, it holds the default route", nic);
+        return Err(GVMError::LinkInUse(nic));
+    }
+
+    let action = match state {
+        LinkState::Up => "up",
+        LinkState::Down => "down",
+    };
+    info!("Bringing {} {}", nic, action);
+    run_privileged(config, &["ip", "link", "set", &nic, action])?;
+
+    if !dry_run() && admin_up(&nic) != (state == LinkState::Up) {
+        return Err(GVMError::LinkStateUnchanged(nic));
+    }
+
+    Ok(())
+}
+
+/// Reads the operational state of the interface `iface`, ie. up, down or unknown.
+pub fn operstate(iface: &str) -> String {
+    let path = "/sys/class/net/".to_owned() + iface + "/operstate";
+
+    match fs::read_to_string(path) {
+        Ok(state) => state.trim().to_owned(),
+        Err(_) => "unknown".to_owned(),
+    }
+}
+
+/// Interval between checks of the operational state of the configured interfaces.
+const LINK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Waits up to `timeout` seconds for every interface in `ifaces` to come up, returning
+/// [GVMError::NetworkNotUp] for the first one which doesn't.
+fn wait_up(ifaces: &[String], timeout: u64) -> Result<(), GVMError> {
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+
+    for iface in ifaces {
+        loop {
+            let state = operstate(iface);
+            if state == "up" {
+                break;
+            } else if Instant::now() >= deadline {
+                warn!("NIC {} did not come up: {}", iface, state);
+                event("link_down", &[("iface", iface), ("state", &state)]);
+                return Err(GVMError::NetworkNotUp(iface.clone()));
+            }
+            thread::sleep(LINK_POLL_INTERVAL);
+        }
+        event("link_up", &[("iface", iface)]);
+    }
+
+    Ok(())
+}
+
+/// Checks if `iface` has a global IPv4 address assigned.
+fn has_address(iface: &str) -> bool {
+    match Command::new("ip")
+        .args(["-4", "-o", "addr", "show", "dev", iface, "scope", "global"])
+        .output()
+    {
+        Ok(output) => output.status.success() && !output.stdout.is_empty(),
+        Err(_) => false,
+    }
+}
+
+/// Waits up to `timeout` seconds for `iface` to get an address from DHCP.
+fn wait_lease(iface: &str, timeout: u64) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+
+    loop {
+        if has_address(iface) {
+            return true;
+        } else if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(LINK_POLL_INTERVAL);
+    }
+}
+
+/// Configures the NIC or bond described by `net` through `renderer`, with the NICs found in
+/// `macs`. Returns the name of the configured interface.
+fn configure_link(
+    renderer: &mut dyn Renderer,
+    net: &Network,
+    macs: &MacMap,
+    config: &Config,
+) -> Result<String, GVMError> {
+    if let Some(link) = &net.link {
+        link.validate()?;
+    }
+    net.vrf()?;
+
+    if let Some(bond) = &net.bond {
+        bond.validate()?;
+        let nics = bond
+            .members
+            .iter()
+            .map(|mac| find_mac(macs, mac))
+            .collect::<Result<Vec<String>, GVMError>>()?;
+        for (nic, mac) in nics.iter().zip(&bond.members) {
+            event(
+                "nic_found",
+                &[("mac", mac), ("iface", nic), ("bond", &bond.name)],
+            );
+            renderer.reconcile(nic, mac)?;
+        }
+        renderer.bond(net, bond, &nics, config)?;
+        return Ok(bond.name.clone());
+    }
+
+    let nic = resolve_interface(macs, net, &config.interface_match)?;
+    event("nic_found", &[("mac", &net.mac), ("iface", &nic)]);
+    renderer.reconcile(&nic, &net.mac)?;
+    renderer.nic(net, &nic, config)?;
+    Ok(nic)
+}
+
+/// Replaces the default route of the NIC of `net`, found in `macs`, with one through its
+/// gateway, inside the table of its VRF if any. The address and the configuration files of
+/// the NIC are left alone, so the next full configuration of the NIC restores its route.
+/// Returns the name of the NIC.
+fn replace_gateway(net: &Network, macs: &MacMap, config: &Config) -> Result<String, GVMError> {
+    let gateway = gateway_only(net)?;
+    let table = net.vrf()?.map(|(_, table)| table.to_string());
+    let nic = resolve_interface(macs, net, &config.interface_match)?;
+
+    let mut args = vec![
+        "ip", "route", "replace", "default", "via", &gateway, "dev", &nic,
+    ];
+    if let Some(table) = &table {
+        args.extend(["table", table]);
+    }
+    run_privileged_checked(config, &args)?;
+
+    event(
+        "gateway_replaced",
+        &[("iface", &nic), ("gateway", &gateway)],
+    );
+    Ok(nic)
+}
+
+/// Adds the static neighbor entries of `net` to `iface`, which has to be up. Entries which
+/// are invalid or fail to be added are reported through `progress`, without failing the NIC.
+fn add_neighbors(net: &Network, iface: &str, config: &Config, progress: &mut dyn FnMut(&str)) {
+    for neighbor in &net.neighbors {
+        let ip = neighbor.ip.to_string();
+        let res = neighbor.validate().and_then(|_| {
+            run_privileged_checked(
+                config,
+                &[
+                    "ip",
+                    "neigh",
+                    "replace",
+                    &ip,
+                    "lladdr",
+                    &neighbor.mac,
+                    "dev",
+                    iface,
+                    "nud",
+                    "permanent",
+                ],
+            )
+        });
+
+        let fields = [
+            ("ip", ip.as_str()),
+            ("mac", &neighbor.mac),
+            ("iface", iface),
+        ];
+        match res {
+            Ok(()) => event("neighbor_added", &fields),
+            Err(err) => {
+                warn!(
+                    "Failed to add neighbor {} on {}: {:?}",
+                    neighbor.ip, iface, err
+                );
+                event("neighbor_failed", &fields);
+                progress(
+                    &("neighbor ".to_owned()
+                        + &ip
+                        + " on "
+                        + iface
+                        + " failed: "
+                        + &err.to_string()),
+                );
+            }
+        }
+    }
+}
+
+/// Validates the sysctl `key` of an interface is a single setting inside the per-interface
+/// namespace, ie. `rp_filter` and not `../all/rp_filter`, and its `value` is a plain value.
+fn validate_sysctl(key: &str, value: &str) -> Result<(), GVMError> {
+    let valid_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid_key {
+        return Err(GVMError::InvalidSysctl(key.to_owned()));
+    }
+
+    let valid_value = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid_value {
+        return Err(GVMError::InvalidSysctl(value.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Sets the sysctls of `net` on `iface`, which has to exist. Every sysctl is reported
+/// through `progress` as set or failed, without failing the NIC.
+fn add_sysctls(net: &Network, iface: &str, config: &Config, progress: &mut dyn FnMut(&str)) {
+    for (key, value) in &net.sysctls {
+        // The slash separated form keeps interface names holding dots, ie. VLANs, intact.
+        let setting = "net/ipv4/conf/".to_owned() + iface + "/" + key + "=" + value;
+        let res = validate_sysctl(key, value)
+            .and_then(|_| run_privileged_checked(config, &["sysctl", "-w", &setting]));
+
+        let fields = [("key", key.as_str()), ("value", value), ("iface", iface)];
+        match res {
+            Ok(()) => {
+                event("sysctl_set", &fields);
+                progress(&("sysctl ".to_owned() + key + "=" + value + " on " + iface + " set"));
+            }
+            Err(err) => {
+                warn!("Failed to set sysctl {} on {}: {:?}", key, iface, err);
+                event("sysctl_failed", &fields);
+                progress(
+                    &("sysctl ".to_owned()
+                        + key
+                        + "="
+                        + value
+                        + " on "
+                        + iface
+                        + " failed: "
+                        + &err.to_string()),
+                );
+            }
+        }
+    }
+}
+
+/// Sets the transmit queue length and toggles the offloads of `net` on `iface`, which has
+/// to be up. Every setting is reported through `progress` as set or failed, without failing
+/// the NIC.
+fn tune_link(net: &Network, iface: &str, config: &Config, progress: &mut dyn FnMut(&str)) {
+    let len = net.txqueuelen.map(|len| len.to_string());
+    let mut settings: Vec<(String, Vec<&str>)> = Vec::new();
+    if let Some(len) = &len {
+        let args = vec!["ip", "link", "set", iface, "txqueuelen", len];
+        settings.push(("txqueuelen ".to_owned() + len, args));
+    }
+    for (offload, enabled) in net.offloads.settings() {
+        let state = if enabled { "on" } else { "off" };
+        let args = vec!["ethtool", "-K", iface, offload, state];
+        settings.push((offload.to_owned() + " " + state, args));
+    }
+
+    for (setting, args) in settings {
+        let fields = [("setting", setting.as_str()), ("iface", iface)];
+        match run_privileged_checked(config, &args) {
+            Ok(()) => {
+                event("link_tuned", &fields);
+                progress(&(setting + " on " + iface + " set"));
+            }
+            Err(err) => {
+                warn!("Failed to set {} on {}: {:?}", setting, iface, err);
+                event("link_tuning_failed", &fields);
+                progress(&(setting + " on " + iface + " failed: " + &err.to_string()));
+            }
+        }
+    }
+}
+
+/// This function is given a vector of network devices and initializes each of them either
+/// using netplan, ifcfg files or systemd-networkd, following the guest `config`.
+///
+/// `progress` is called with a short human readable message as each NIC gets configured,
+/// so the caller can report intermediate progress before the final result. NICs and bonds
+/// trying DHCP report whether they got a lease or fell back to their static address. The names of
+/// the configured interfaces are returned once the NICs and bonds among them are up.
+pub fn init_net(
+    nets: &Vec<Network>,
+    config: &Config,
+    progress: &mut dyn FnMut(&str),
+) -> Result<Vec<String>, GVMError> {
+    info!("Initializing network");
+
+    let nets_len = nets.len();
+    let backend = detect_backend()?;
+    let mut renderer = configured_renderer(backend, config)?;
+    let mut ifaces: Vec<String> = Vec::new();
+    // Tunnels stay in the unknown state, so only NICs and bonds are waited for.
+    let mut links: Vec<String> = Vec::new();
+
+    match backend {
+        Backend::Netplan => info!("Using netplan"),
+        Backend::Ifcfg => info!("Using systemd networking"),
+        Backend::Networkd => info!("Using systemd-networkd"),
+    }
+    let backend_name = format!("{:?}", backend).to_lowercase();
+    event("backend_chosen", &[("backend", &backend_name)]);
+
+    // Bad addresses and tunnels fail before any file is written, rather than half applying
+    // the networks.
+    for net in nets {
+        validate_address(net)?;
+        if let Some(wg) = &net.wireguard {
+            wg.validate()?;
+        }
+    }
+
+    let macs = scan_macs()?;
+    let mut loopback: Vec<IpAddr> = Vec::new();
+    // NICs and bonds trying DHCP, along with their interface.
+    let mut dhcp: Vec<(&Network, String)> = Vec::new();
+    // NICs and bonds with a static address using the configured DNS servers.
+    let mut dns: Vec<String> = Vec::new();
+    // NICs and bonds with static neighbor entries, along with their interface.
+    let mut neighbors: Vec<(&Network, String)> = Vec::new();
+    // NICs and bonds with sysctls, along with their interface.
+    let mut sysctls: Vec<(&Network, String)> = Vec::new();
+    // NICs and bonds setting their DNSSEC or DNS-over-TLS mode, along with their interface.
+    let mut dns_security: Vec<(&Network, String)> = Vec::new();
+    // NICs protected by MACsec, along with their interface.
+    let mut macsec: Vec<(&Network, String)> = Vec::new();
+    // NICs and bonds with a transmit queue length or offloads, along with their interface.
+    let mut tuning: Vec<(&Network, String)> = Vec::new();
+
+    for net in nets {
+        info!("Adding {:#?}", net);
+
+        if net.loopback {
+            loopback.push(loopback_address(net)?);
+            continue;
+        }
+
+        if let Some(wg) = &net.wireguard {
+            renderer.wireguard(net, wg)?;
+            progress(&("configured ".to_owned() + &wg.name));
+            ifaces.push(wg.name.clone());
+            continue;
+        }
+
+        // Changing the route of a running NIC needs no configuration file.
+        if net.gateway_only() {
+            let nic = replace_gateway(net, &macs, config)?;
+            progress(&("replaced gateway of ".to_owned() + &nic));
+            ifaces.push(nic);
+            continue;
+        }
+
+        let link = configure_link(renderer.as_mut(), net, &macs, config).inspect_err(|err| {
+            event(
+                "nic_failed",
+                &[("mac", &net.mac), ("reason", &err.to_string())],
+            );
+        })?;
+        event(
+            "nic_configured",
+            &[
+                ("mac", &net.mac),
+                ("iface", &link),
+                ("backend", &backend_name),
+                ("ip", &address_text(net)),
+            ],
+        );
+        progress(&("configured ".to_owned() + &link));
+        if net.addressing == Addressing::DhcpFallback {
+            dhcp.push((net, link.clone()));
+        } else if net.configures_dns() {
+            dns.push(link.clone());
+        }
+        if !net.neighbors.is_empty() {
+            neighbors.push((net, link.clone()));
+        }
+        if !net.sysctls.is_empty() {
+            sysctls.push((net, link.clone()));
+        }
+        if net.dns_security() {
+            dns_security.push((net, link.clone()));
+        }
+        if net.macsec.is_some() {
+            macsec.push((net, link.clone()));
+        }
+        if net.txqueuelen.is_some() || !net.offloads.settings().is_empty() {
+            tuning.push((net, link.clone()));
+        }
+        links.push(link.clone());
+        ifaces.push(link);
+    }
+
+    // Every alias lives in the same loopback configuration, so they are written at once.
+    if !loopback.is_empty() {
+        renderer.loopback(&loopback)?;
+        progress("configured lo");
+        ifaces.push("lo".to_owned());
+    }
+
+    if nets_len > 0 && !nets.iter().all(Network::gateway_only) {
+        renderer.apply(config, &links)?;
+        event(
+            "network_applied",
+            &[("backend", &backend_name), ("links", &links.join(","))],
+        );
+    }
+
+    // Nothing was applied during a dry run, so links down stay down.
+    if config.link_timeout > 0 && !dry_run() {
+        wait_up(&links, config.link_timeout)?;
+    }
+    resolved_dns(&dns, config)?;
+    for (net, link) in &neighbors {
+        add_neighbors(net, link, config, progress);
+    }
+    // Sysctls outlive address changes, so NICs falling back from DHCP keep them.
+    for (net, link) in &sysctls {
+        add_sysctls(net, link, config, progress);
+    }
+    for (net, link) in &dns_security {
+        resolved_dns_security(net, link, config)?;
+    }
+    for (net, link) in &macsec {
+        report_macsec(net, link, config, progress);
+    }
+    for (net, link) in &tuning {
+        tune_link(net, link, config, progress);
+    }
+
+    // Nothing was applied during a dry run, so no lease can show up.
+    if dry_run() {
+        for (_, link) in &dhcp {
+            info!("Would wait for a DHCP lease on {}", link);
+        }
+        return Ok(ifaces);
+    }
+
+    // The interfaces without a lease in time get their static address instead.
+    let mut fallback: Vec<String> = Vec::new();
+    let mut fallback_dns: Vec<String> = Vec::new();
+    let mut fallback_neighbors: Vec<(Network, String)> = Vec::new();
+    let mut fallback_security: Vec<(Network, String)> = Vec::new();
+    for (net, link) in dhcp {
+        if wait_lease(&link, config.dhcp_timeout) {
+            event("dhcp_leased", &[("iface", &link)]);
+            progress(&("leased ".to_owned() + &link + " through dhcp"));
+            continue;
+        }
+
+        warn!(
+            "No DHCP lease on {}, falling back to {}",
+            link,
+            address_text(net)
+        );
+        event(
+            "dhcp_fallback",
+            &[("iface", &link), ("ip", &address_text(net))],
+        );
+        let mut net = net.clone();
+        net.addressing = Addressing::Static;
+        configure_link(renderer.as_mut(), &net, &macs, config)?;
+        progress(&("configured ".to_owned() + &link + " statically, no dhcp lease"));
+        if net.configures_dns() {
+            fallback_dns.push(link.clone());
+        }
+        if net.dns_security() {
+            fallback_security.push((net.clone(), link.clone()));
+        }
+        if !net.neighbors.is_empty() {
+            fallback_neighbors.push((net, link.clone()));
+        }
+        fallback.push(link);
+    }
+
+    if !fallback.is_empty() {
+        renderer.apply(config, &fallback)?;
+        event(
+            "network_applied",
+            &[("backend", &backend_name), ("links", &fallback.join(","))],
+        );
+        if config.link_timeout > 0 {
+            wait_up(&fallback, config.link_timeout)?;
+        }
+    }
+    resolved_dns(&fallback_dns, config)?;
+    // Reapplying the configuration may have flushed the entries added before.
+    for (net, link) in &fallback_neighbors {
+        add_neighbors(net, link, config, progress);
+    }
+    for (net, link) in &fallback_security {
+        resolved_dns_security(net, link, config)?;
+    }
+
+    Ok(ifaces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(json: &str) -> Network {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn prefix_taken_from_prefix_then_address() {
+        let net = network(r#"{"ip":"10.0.0.2/16","gateway":"10.0.0.1","prefix":24}"#);
+        assert_eq!(
+            split_address(&net).unwrap(),
+            (Some("10.0.0.1".to_owned()), 24)
+        );
+
+        let net = network(r#"{"ip":"10.0.0.2/16","gateway":"10.0.0.1"}"#);
+        assert_eq!(
+            split_address(&net).unwrap(),
+            (Some("10.0.0.1".to_owned()), 16)
+        );
+    }
+
+    #[test]
+    fn prefix_taken_from_legacy_gateway() {
+        let net = network(r#"{"ip":"10.0.0.2","gateway":"10.0.0.1/24"}"#);
+        assert_eq!(
+            split_address(&net).unwrap(),
+            (Some("10.0.0.1".to_owned()), 24)
+        );
+
+        // The address keeps precedence over the legacy form.
+        let net = network(r#"{"ip":"10.0.0.2/16","gateway":"10.0.0.1/24"}"#);
+        assert_eq!(
+            split_address(&net).unwrap(),
+            (Some("10.0.0.1".to_owned()), 16)
+        );
+    }
+
+    #[test]
+    fn missing_prefix_rejected() {
+        let net = network(r#"{"ip":"10.0.0.2","gateway":"10.0.0.1"}"#);
+        assert!(matches!(
+            split_address(&net),
+            Err(GVMError::InvalidPrefix(ip)) if ip == "10.0.0.2"
+        ));
+
+        let net = network(r#"{"ip":"fd00::2/64"}"#);
+        assert!(matches!(
+            split_address(&net),
+            Err(GVMError::InvalidPrefix(_))
+        ));
+    }
+
+    #[test]
+    fn flat_segment_has_no_gateway() {
+        let net = network(r#"{"ip":"10.0.0.2","prefix":24}"#);
+        assert_eq!(split_address(&net).unwrap(), (None, 24));
+    }
+}
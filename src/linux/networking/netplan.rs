@@ -0,0 +1,492 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This configures networking through netplan, every interface gets its own YAML file inside
+//! /etc/netplan and WireGuard tunnels are written as netplan tunnels.
+use super::{
+    dns_servers, host_prefix, listed_files, matching_files, policy_routes, remove_file,
+    run_privileged, split_address, write_config, write_link, Renderer, LINK_PREFIX, NETWORKD_DIR,
+};
+use crate::common::{Addressing, Bond, GVMError, Network, WireGuard};
+use crate::config::{Config, NetplanApply};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::result::Result;
+
+/// Prefix of the netplan files written by the guest, one per interface.
+const NETPLAN_PREFIX: &str = "/etc/netplan/90-gvm-";
+
+/// Writes the netplan file of the interface `name`, holding the interface definitions of
+/// every non-empty section in `sections`. The file is only readable by root, as it may
+/// contain addresses and keys.
+fn netplan_write(name: &str, sections: &[(&str, &str)]) -> Result<(), GVMError> {
+    let file_name = NETPLAN_PREFIX.to_owned() + name + ".yaml";
+    let mut contents = "network:".to_owned();
+
+    for (section, entries) in sections {
+        if !entries.is_empty() {
+            contents = contents + "\n  " + section + ":" + entries;
+        }
+    }
+
+    contents = contents + "\n" + "  version: 2\n";
+    write_config(&file_name, &contents, 0o600)
+}
+
+/// Lists the netplan files written by the guest.
+fn netplan_files() -> Result<Vec<String>, GVMError> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir("/etc/netplan")? {
+        let path = entry?.path().to_string_lossy().into_owned();
+        if path.starts_with(NETPLAN_PREFIX) && path.ends_with(".yaml") {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Provides the netplan addressing, gateway and DNS configuration of `net`, shared by all
+/// interface kinds carrying an address.
+fn netplan_addressing(net: &Network, config: &Config) -> Result<String, GVMError> {
+    let (gateway, cidr) = split_address(net)?;
+
+    let privacy = match net.ipv6_privacy {
+        Some(privacy) => "      ipv6-privacy: ".to_owned() + &privacy.to_string() + "\n",
+        None => "".to_owned(),
+    };
+
+    if net.addressing == Addressing::DhcpFallback {
+        let mut overrides = Vec::new();
+        if !net.configures_dns() {
+            overrides.push("use-dns: false".to_owned());
+        }
+        if let Some(metric) = net.metric {
+            overrides.push("route-metric: ".to_owned() + &metric.to_string());
+        }
+
+        let mut ret = privacy + "      dhcp4: true";
+        if !overrides.is_empty() {
+            ret = ret + "\n      dhcp4-overrides:\n        " + &overrides.join("\n        ");
+        }
+        return Ok(ret);
+    }
+
+    let mut ret = privacy
+        + "      dhcp4: false\n"
+        + "      addresses:\n"
+        + "        - "
+        + &net.address()?.to_string()
+        + "/"
+        + &cidr.to_string();
+
+    // Inside a VRF the default route lives in the VRF table, see [netplan_vrf]. gateway4
+    // can't carry a metric, the default route is then listed with the other routes.
+    let default_route = match (gateway, net.vrf()?, net.metric) {
+        (Some(gateway), None, Some(metric)) => Some((gateway, metric)),
+        (Some(gateway), None, None) => {
+            ret = ret + "\n      gateway4: " + &gateway;
+            None
+        }
+        _ => None,
+    };
+
+    let servers = dns_servers(net, config);
+    if !servers.is_empty() {
+        ret = ret + "\n      nameservers:\n" + "        addresses: [" + &servers.join(", ") + "]";
+    }
+
+    let routes = policy_routes(net)?;
+    if default_route.is_some() || !routes.is_empty() {
+        ret += "\n      routes:";
+    }
+    if let Some((gateway, metric)) = default_route {
+        ret = ret
+            + "\n        - to: default"
+            + "\n          via: "
+            + &gateway
+            + "\n          metric: "
+            + &metric.to_string();
+    }
+    if !routes.is_empty() {
+        for (policy, via) in &routes {
+            ret = ret
+                + "\n        - to: "
+                + &policy
+                    .to
+                    .map_or("0.0.0.0/0".to_owned(), |to| to.to_string())
+                + "\n          via: "
+                + via
+                + "\n          table: "
+                + &policy.table.to_string();
+        }
+
+        ret += "\n      routing-policy:";
+        for (policy, _) in &routes {
+            let mut keys = Vec::new();
+            if let Some(from) = &policy.from {
+                keys.push("from: ".to_owned() + &from.to_string());
+            }
+            if let Some(to) = &policy.to {
+                keys.push("to: ".to_owned() + &to.to_string());
+            }
+            keys.push("table: ".to_owned() + &policy.table.to_string());
+            ret = ret + "\n        - " + &keys.join("\n          ");
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Provides the netplan ethernet entry matching the NIC with the `mac` address as `nic`,
+/// `body` holds the rest of its configuration. Without `mac` the NIC is matched by name.
+fn netplan_ethernet(nic: &str, mac: &str, body: &str) -> String {
+    let mut ret = "".to_owned() + "    " + nic + ":\n";
+
+    if !mac.is_empty() {
+        ret = ret + "      match:\n" + "        macaddress: " + &mac.to_lowercase() + "\n";
+    }
+
+    ret + body
+}
+
+/// This function is to provide for us the incremental configuration for the
+/// valid `net` device, found as `nic`, inside the GVM guest program.
+fn netplan_networking(net: &Network, nic: &str, config: &Config) -> Result<String, GVMError> {
+    Ok(netplan_ethernet(
+        nic,
+        &net.mac,
+        &netplan_addressing(net, config)?,
+    ))
+}
+
+/// This function provides the netplan configuration of the `bond` described by `net`, with
+/// its members found as `nics`. The members are returned as ethernet entries, and the bond
+/// itself as a bond entry carrying the address.
+fn netplan_bond(
+    net: &Network,
+    bond: &Bond,
+    nics: &[String],
+    config: &Config,
+) -> Result<(String, String), GVMError> {
+    let mut ethernets = "".to_owned();
+
+    for (nic, mac) in nics.iter().zip(&bond.members) {
+        ethernets = ethernets + "\n" + &netplan_ethernet(nic, mac, "      dhcp4: false");
+    }
+
+    let bonds = "".to_owned()
+        + "\n    "
+        + &bond.name
+        + ":\n"
+        + "      interfaces: ["
+        + &nics.join(", ")
+        + "]\n"
+        + "      parameters:\n"
+        + "        mode: "
+        + &bond.mode
+        + "\n"
+        + &netplan_addressing(net, config)?;
+
+    Ok((ethernets, bonds))
+}
+
+/// This function provides the netplan tunnel configuration for the WireGuard tunnel `wg`
+/// described by `net`.
+fn netplan_wireguard(net: &Network, wg: &WireGuard) -> Result<String, GVMError> {
+    let (_, cidr) = split_address(net)?;
+
+    let mut ret = "".to_owned()
+        + "    "
+        + &wg.name
+        + ":\n"
+        + "      mode: wireguard\n"
+        + "      addresses:\n"
+        + "        - "
+        + &net.address()?.to_string()
+        + "/"
+        + &cidr.to_string()
+        + "\n"
+        + "      key: "
+        + &wg.private_key
+        + "\n";
+
+    if let Some(port) = wg.listen_port {
+        ret = ret + "      port: " + &port.to_string() + "\n";
+    }
+
+    ret = ret
+        + "      peers:\n"
+        + "        - keys:\n"
+        + "            public: "
+        + &wg.peer_public_key
+        + "\n";
+
+    if let Some(endpoint) = &wg.endpoint {
+        ret = ret + "          endpoint: " + endpoint + "\n";
+    }
+
+    ret = ret + "          allowed-ips: [" + &wg.allowed_ips.join(", ") + "]";
+
+    Ok(ret)
+}
+
+/// Provides the netplan VRF definition enslaving `iface` to the VRF of `net`, holding the
+/// default route through the gateway of `net` inside the VRF table. Empty when `net` has no
+/// VRF.
+fn netplan_vrf(net: &Network, iface: &str) -> Result<String, GVMError> {
+    let (vrf, table) = match net.vrf()? {
+        Some(vrf) => vrf,
+        None => return Ok("".to_owned()),
+    };
+    let (gateway, _) = split_address(net)?;
+
+    let mut ret = "".to_owned()
+        + "\n    "
+        + vrf
+        + ":\n"
+        + "      table: "
+        + &table.to_string()
+        + "\n"
+        + "      interfaces: ["
+        + iface
+        + "]";
+
+    if let Some(gateway) = gateway {
+        ret = ret + "\n      routes:\n" + "        - to: default\n" + "          via: " + &gateway;
+        if let Some(metric) = net.metric {
+            ret = ret + "\n          metric: " + &metric.to_string();
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Applies the netplan configuration written to /etc/netplan using the configured strategy.
+fn netplan_apply(config: &Config) -> Result<(), GVMError> {
+    match config.netplan_apply {
+        NetplanApply::Apply => run_privileged(config, &["netplan", "apply"]),
+        NetplanApply::Generate => {
+            run_privileged(config, &["netplan", "generate"])?;
+            run_privileged(config, &["networkctl", "reload"])
+        }
+    }
+}
+
+/// Counts the leading spaces of `line`.
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Removes the interface definitions matching `mac`, or named `nic`, from the netplan
+/// configuration in `contents`. VRFs holding a removed interface and sections left without
+/// any interface are removed as well. Returns `None` if no interface matched.
+fn netplan_remove(contents: &str, mac: &str, nic: Option<&str>) -> Option<String> {
+    let lines: Vec<&str> = contents.lines().collect();
+    let needle = "macaddress: ".to_owned() + &mac.to_lowercase();
+    let header = nic.map(|nic| nic.to_owned() + ":");
+    let mut blocks: Vec<&[&str]> = Vec::new();
+    let mut removed_names: Vec<String> = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        // Interface definitions live at 4 spaces, ie. network -> ethernets -> nic.
+        if indent(lines[i]) != 4 {
+            blocks.push(&lines[i..i + 1]);
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        i += 1;
+        while i < lines.len() && (lines[i].trim().is_empty() || indent(lines[i]) > 4) {
+            i += 1;
+        }
+
+        let block = &lines[start..i];
+        let by_mac = !mac.is_empty()
+            && block
+                .iter()
+                .any(|line| line.trim().replace('"', "").to_lowercase() == needle);
+        let by_name = header.as_deref() == Some(lines[start].trim());
+        if by_mac || by_name {
+            removed_names.push(lines[start].trim().trim_end_matches(':').to_owned());
+        } else {
+            blocks.push(block);
+        }
+    }
+
+    if removed_names.is_empty() {
+        return None;
+    }
+
+    // VRFs only hold the interface they were written for, see [netplan_vrf].
+    let members: Vec<String> = removed_names
+        .iter()
+        .map(|name| "interfaces: [".to_owned() + name + "]")
+        .collect();
+    let kept: Vec<&str> = blocks
+        .into_iter()
+        .filter(|block| {
+            !block
+                .iter()
+                .any(|line| members.iter().any(|member| line.trim() == member))
+        })
+        .flatten()
+        .copied()
+        .collect();
+
+    // Drop section headers which no longer hold any interfaces.
+    let sections: Vec<&str> = kept
+        .iter()
+        .enumerate()
+        .filter(|(i, line)| {
+            let header = indent(line) == 2 && line.trim_end().ends_with(':');
+            !header || kept.get(i + 1).is_some_and(|next| indent(next) > 2)
+        })
+        .map(|(_, line)| *line)
+        .collect();
+
+    Some(sections.join("\n") + "\n")
+}
+
+/// Removes the interface definitions matching `mac` or `nic` from the netplan file
+/// `file_name`, deleting the file once no interface is left inside of it. Returns `false` if
+/// no interface matched.
+fn netplan_strip(file_name: &str, mac: &str, nic: Option<&str>) -> Result<bool, GVMError> {
+    let contents = fs::read_to_string(file_name)?;
+
+    match netplan_remove(&contents, mac, nic) {
+        Some(contents) if contents.trim() == "network:\n  version: 2" => {
+            info!("Removing {}", file_name);
+            remove_file(file_name)?;
+        }
+        Some(contents) => {
+            info!("Removing {} from {}", nic.unwrap_or(mac), file_name);
+            write_config(file_name, &contents, 0o600)?;
+        }
+        None => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// Searches for the link files written for the `mac` address. Link files live with the
+/// systemd-networkd configuration, see [write_link].
+fn netplan_links(mac: &str) -> Result<Vec<String>, GVMError> {
+    if !Path::new(NETWORKD_DIR).exists() {
+        return Ok(Vec::new());
+    }
+
+    let needles = ["MACAddress=".to_owned() + mac];
+    matching_files(
+        NETWORKD_DIR,
+        |name| name.starts_with(LINK_PREFIX) && name.ends_with(".link"),
+        &needles,
+    )
+}
+
+/// Renders the network configuration as netplan YAML.
+pub struct Netplan;
+
+impl Renderer for Netplan {
+    fn nic(&mut self, net: &Network, nic: &str, config: &Config) -> Result<(), GVMError> {
+        let ethernet = "\n".to_owned() + &netplan_networking(net, nic, config)?;
+        let vrfs = netplan_vrf(net, nic)?;
+        netplan_write(nic, &[("ethernets", &ethernet), ("vrfs", &vrfs)])?;
+        write_link(nic, &net.mac, net.link.as_ref(), config)
+    }
+
+    fn bond(
+        &mut self,
+        net: &Network,
+        bond: &Bond,
+        nics: &[String],
+        config: &Config,
+    ) -> Result<(), GVMError> {
+        let (members, bonds) = netplan_bond(net, bond, nics, config)?;
+        let vrfs = netplan_vrf(net, &bond.name)?;
+        netplan_write(
+            &bond.name,
+            &[("ethernets", &members), ("bonds", &bonds), ("vrfs", &vrfs)],
+        )?;
+        for (nic, mac) in nics.iter().zip(&bond.members) {
+            write_link(nic, mac, net.link.as_ref(), config)?;
+        }
+        Ok(())
+    }
+
+    fn wireguard(&mut self, net: &Network, wg: &WireGuard) -> Result<(), GVMError> {
+        let tunnel = "\n".to_owned() + &netplan_wireguard(net, wg)?;
+        netplan_write(&wg.name, &[("tunnels", &tunnel)])
+    }
+
+    fn loopback(&mut self, addrs: &[IpAddr]) -> Result<(), GVMError> {
+        let mut body = "      addresses:".to_owned();
+        for addr in addrs {
+            body = body + "\n        - " + &addr.to_string() + "/" + &host_prefix(addr).to_string();
+        }
+
+        let ethernet = "\n".to_owned() + &netplan_ethernet("lo", "", &body);
+        netplan_write("lo", &[("ethernets", &ethernet)])
+    }
+
+    fn apply(&self, config: &Config, _links: &[String]) -> Result<(), GVMError> {
+        netplan_apply(config)
+    }
+
+    fn reconcile(&self, nic: &str, mac: &str) -> Result<(), GVMError> {
+        for entry in fs::read_dir("/etc/netplan")? {
+            let path = entry?.path().to_string_lossy().into_owned();
+            if !path.starts_with(NETPLAN_PREFIX) && path.ends_with(".yaml") {
+                netplan_strip(&path, mac, Some(nic))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, mac: &str, config: &Config) -> Result<bool, GVMError> {
+        let mut removed = false;
+
+        for file_name in netplan_files()? {
+            removed |= netplan_strip(&file_name, mac, None)?;
+        }
+
+        for file in netplan_links(mac)? {
+            info!("Removing {}", file);
+            remove_file(file)?;
+            removed = true;
+        }
+
+        if removed {
+            netplan_apply(config)?;
+        }
+
+        Ok(removed)
+    }
+
+    fn files(&self, mac: &str) -> Result<Vec<String>, GVMError> {
+        let mut files = Vec::new();
+
+        for file_name in netplan_files()? {
+            let contents = fs::read_to_string(&file_name)?;
+            if netplan_remove(&contents, mac, None).is_some() {
+                files.push(file_name);
+            }
+        }
+        files.extend(netplan_links(mac)?);
+
+        Ok(files)
+    }
+
+    fn owned(&self) -> Result<Vec<String>, GVMError> {
+        let mut files = netplan_files()?;
+        files.extend(listed_files(NETWORKD_DIR, |name| {
+            name.starts_with(LINK_PREFIX) && name.ends_with(".link")
+        })?);
+
+        Ok(files)
+    }
+}
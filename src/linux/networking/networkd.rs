@@ -0,0 +1,343 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This configures networking through systemd-networkd .network and .netdev files inside
+//! /etc/systemd/network, WireGuard tunnels are brought up through wg-quick.
+use super::{
+    dns_servers, host_prefix, listed_files, matching_files, policy_routes, remove_file,
+    run_privileged, split_address, wg_quick_enable, wg_quick_networking, wg_quick_owned,
+    write_config, write_link, Renderer, NETWORKD_DIR,
+};
+use crate::common::{Addressing, Bond, GVMError, Network, WireGuard};
+use crate::config::Config;
+use std::net::IpAddr;
+use std::result::Result;
+
+/// Prefix of the systemd-networkd files written by the guest.
+const NETWORKD_PREFIX: &str = "10-gvm-";
+
+/// Provides the networkd network section holding the addressing, gateway and DNS
+/// configuration of `net`, shared by all interface kinds carrying an address.
+fn networkd_addressing(net: &Network, config: &Config) -> Result<String, GVMError> {
+    let (gateway, cidr) = split_address(net)?;
+
+    let privacy = match net.ipv6_privacy {
+        Some(true) => "IPv6PrivacyExtensions=yes\n",
+        Some(false) => "IPv6PrivacyExtensions=no\n",
+        None => "",
+    };
+
+    // Routes of an interface inside a VRF, its gateway included, land in the VRF table.
+    let vrf = match net.vrf()? {
+        Some((vrf, _)) => "VRF=".to_owned() + vrf + "\n",
+        None => "".to_owned(),
+    };
+    let security = networkd_dns_security(net);
+
+    if net.addressing == Addressing::DhcpFallback {
+        let mut dhcp = "".to_owned();
+        if !net.configures_dns() {
+            dhcp += "UseDNS=false\n";
+        }
+        if let Some(metric) = net.metric {
+            dhcp = dhcp + "RouteMetric=" + &metric.to_string() + "\n";
+        }
+
+        let mut ret = "".to_owned() + "[Network]\n" + "DHCP=ipv4\n" + privacy + &security + &vrf;
+        if !dhcp.is_empty() {
+            ret = ret + "\n" + "[DHCPv4]\n" + &dhcp;
+        }
+        return Ok(ret);
+    }
+
+    let dns: String = dns_servers(net, config)
+        .iter()
+        .map(|server| "DNS=".to_owned() + server + "\n")
+        .collect();
+
+    let mut ret = "".to_owned()
+        + "[Network]\n"
+        + "Address="
+        + &net.address()?.to_string()
+        + "/"
+        + &cidr.to_string()
+        + "\n";
+
+    // Gateway= can't carry a metric, the default route then gets a section of its own.
+    let default_route = match (gateway, net.metric) {
+        (Some(gateway), Some(metric)) => Some((gateway, metric)),
+        (Some(gateway), None) => {
+            ret = ret + "Gateway=" + &gateway + "\n";
+            None
+        }
+        (None, _) => None,
+    };
+    ret = ret + privacy + &security + &vrf + &dns;
+
+    if let Some((gateway, metric)) = default_route {
+        ret = ret
+            + "\n"
+            + "[Route]\n"
+            + "Gateway="
+            + &gateway
+            + "\n"
+            + "Metric="
+            + &metric.to_string()
+            + "\n";
+    }
+
+    // Invalid entries are skipped here, they are reported once the interface is up.
+    for neighbor in net
+        .neighbors
+        .iter()
+        .filter(|neighbor| neighbor.validate().is_ok())
+    {
+        ret = ret
+            + "\n"
+            + "[Neighbor]\n"
+            + "Address="
+            + &neighbor.ip.to_string()
+            + "\n"
+            + "LinkLayerAddress="
+            + &neighbor.mac
+            + "\n";
+    }
+
+    for (policy, via) in policy_routes(net)? {
+        let table = policy.table.to_string();
+
+        ret = ret + "\n" + "[Route]\n";
+        if let Some(to) = &policy.to {
+            ret = ret + "Destination=" + &to.to_string() + "\n";
+        }
+        ret = ret + "Gateway=" + &via + "\n" + "Table=" + &table + "\n";
+
+        ret = ret + "\n" + "[RoutingPolicyRule]\n";
+        if let Some(from) = &policy.from {
+            ret = ret + "From=" + &from.to_string() + "\n";
+        }
+        if let Some(to) = &policy.to {
+            ret = ret + "To=" + &to.to_string() + "\n";
+        }
+        ret = ret + "Table=" + &table + "\n";
+    }
+
+    Ok(ret)
+}
+
+/// Provides the networkd DNSSEC and DNS-over-TLS settings of `net`, empty when it leaves
+/// the system settings untouched.
+fn networkd_dns_security(net: &Network) -> String {
+    let yes_no = |enabled: bool| if enabled { "yes" } else { "no" };
+
+    let mut ret = "".to_owned();
+    if let Some(dnssec) = net.dnssec {
+        ret = ret + "DNSSEC=" + yes_no(dnssec) + "\n";
+    }
+    if let Some(dns_over_tls) = net.dns_over_tls {
+        ret = ret + "DNSOverTLS=" + yes_no(dns_over_tls) + "\n";
+    }
+    ret
+}
+
+/// Provides the networkd match section of the NIC with the `mac` address as `nic`. Without
+/// `mac` the NIC is matched by name.
+fn networkd_match(nic: &str, mac: &str) -> String {
+    if mac.is_empty() {
+        "".to_owned() + "[Match]\n" + "Name=" + nic + "\n"
+    } else {
+        "".to_owned() + "[Match]\n" + "MACAddress=" + &mac.to_lowercase() + "\n"
+    }
+}
+
+/// Writes the networkd file of the interface `name`, `extension` is either .network or
+/// .netdev.
+fn networkd_write(name: &str, extension: &str, contents: &str) -> Result<(), GVMError> {
+    let file_name = NETWORKD_DIR.to_owned() + "/" + NETWORKD_PREFIX + name + extension;
+    write_config(&file_name, contents, 0o644)
+}
+
+/// This function configures the networkd network file for a given `net`, found as `nic`.
+fn networkd_networking(net: &Network, nic: &str, config: &Config) -> Result<(), GVMError> {
+    let contents = networkd_match(nic, &net.mac) + "\n" + &networkd_addressing(net, config)?;
+    networkd_write(nic, ".network", &contents)
+}
+
+/// This function configures the networkd files of the `bond` described by `net`, with its
+/// members found as `nics`. The bond is created through a netdev file and carries the
+/// address, while the members are enslaved to it without any addressing of their own.
+fn networkd_bond(
+    net: &Network,
+    bond: &Bond,
+    nics: &[String],
+    config: &Config,
+) -> Result<(), GVMError> {
+    for (nic, mac) in nics.iter().zip(&bond.members) {
+        let contents =
+            networkd_match(nic, mac) + "\n" + "[Network]\n" + "Bond=" + &bond.name + "\n";
+        networkd_write(nic, ".network", &contents)?;
+    }
+
+    let netdev = "".to_owned()
+        + "[NetDev]\n"
+        + "Name="
+        + &bond.name
+        + "\n"
+        + "Kind=bond\n"
+        + "\n"
+        + "[Bond]\n"
+        + "Mode="
+        + &bond.mode
+        + "\n"
+        + "MIIMonitorSec=100ms\n";
+    networkd_write(&bond.name, ".netdev", &netdev)?;
+
+    let contents = networkd_match(&bond.name, "") + "\n" + &networkd_addressing(net, config)?;
+    networkd_write(&bond.name, ".network", &contents)
+}
+
+/// This function configures the networkd files of the VRF of `net`, if any. The VRF is
+/// created through a netdev file bound to its routing table, and brought up without any
+/// addressing of its own.
+fn networkd_vrf(net: &Network) -> Result<(), GVMError> {
+    let Some((vrf, table)) = net.vrf()? else {
+        return Ok(());
+    };
+
+    let netdev = "".to_owned()
+        + "[NetDev]\n"
+        + "Name="
+        + vrf
+        + "\n"
+        + "Kind=vrf\n"
+        + "\n"
+        + "[VRF]\n"
+        + "Table="
+        + &table.to_string()
+        + "\n";
+    networkd_write(vrf, ".netdev", &netdev)?;
+
+    networkd_write(vrf, ".network", &networkd_match(vrf, ""))
+}
+
+/// Searches /etc/systemd/network for the networkd files written for the `mac` address.
+fn networkd_files(mac: &str) -> Result<Vec<String>, GVMError> {
+    let needles = ["macaddress=".to_owned() + mac];
+    matching_files(
+        NETWORKD_DIR,
+        |name| name.starts_with(NETWORKD_PREFIX),
+        &needles,
+    )
+}
+
+/// Reloads the systemd-networkd configuration, and reconfigures the interfaces `links` so
+/// they pick up their new files.
+fn networkd_apply(config: &Config, links: &[String]) -> Result<(), GVMError> {
+    run_privileged(config, &["networkctl", "reload"])?;
+
+    if !links.is_empty() {
+        let mut args = vec!["networkctl", "reconfigure"];
+        args.extend(links.iter().map(|link| link.as_str()));
+        run_privileged(config, &args)?;
+    }
+
+    Ok(())
+}
+
+/// Renders the network configuration as systemd-networkd files. `wg_quick` holds the
+/// tunnels to enable once applied.
+#[derive(Default)]
+pub struct Networkd {
+    wg_quick: Vec<String>,
+}
+
+impl Renderer for Networkd {
+    fn nic(&mut self, net: &Network, nic: &str, config: &Config) -> Result<(), GVMError> {
+        networkd_networking(net, nic, config)?;
+        networkd_vrf(net)?;
+        write_link(nic, &net.mac, net.link.as_ref(), config)
+    }
+
+    fn bond(
+        &mut self,
+        net: &Network,
+        bond: &Bond,
+        nics: &[String],
+        config: &Config,
+    ) -> Result<(), GVMError> {
+        networkd_bond(net, bond, nics, config)?;
+        networkd_vrf(net)?;
+        for (nic, mac) in nics.iter().zip(&bond.members) {
+            write_link(nic, mac, net.link.as_ref(), config)?;
+        }
+        Ok(())
+    }
+
+    fn wireguard(&mut self, net: &Network, wg: &WireGuard) -> Result<(), GVMError> {
+        wg_quick_networking(net, wg)?;
+        self.wg_quick.push(wg.name.clone());
+        Ok(())
+    }
+
+    fn loopback(&mut self, addrs: &[IpAddr]) -> Result<(), GVMError> {
+        let mut contents = networkd_match("lo", "") + "\n" + "[Network]\n";
+        for addr in addrs {
+            contents = contents + "Address=" + &addr.to_string() + "/";
+            contents = contents + &host_prefix(addr).to_string() + "\n";
+        }
+
+        networkd_write("lo", ".network", &contents)
+    }
+
+    fn apply(&self, config: &Config, links: &[String]) -> Result<(), GVMError> {
+        networkd_apply(config, links)?;
+        wg_quick_enable(config, &self.wg_quick)
+    }
+
+    fn reconcile(&self, nic: &str, mac: &str) -> Result<(), GVMError> {
+        let mut needles = Vec::new();
+        if !mac.is_empty() {
+            needles.push("MACAddress=".to_owned() + mac);
+        }
+        needles.push("Name=".to_owned() + nic);
+
+        let files = matching_files(
+            NETWORKD_DIR,
+            |name| !name.starts_with(NETWORKD_PREFIX) && name.ends_with(".network"),
+            &needles,
+        )?;
+
+        for file in files {
+            info!("Removing conflicting {}", file);
+            remove_file(file)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, mac: &str, config: &Config) -> Result<bool, GVMError> {
+        let files = networkd_files(mac)?;
+        if files.is_empty() {
+            return Ok(false);
+        }
+
+        for file in files {
+            info!("Removing {}", file);
+            remove_file(file)?;
+        }
+
+        run_privileged(config, &["networkctl", "reload"])?;
+        Ok(true)
+    }
+
+    fn files(&self, mac: &str) -> Result<Vec<String>, GVMError> {
+        networkd_files(mac)
+    }
+
+    // The link files pinning the link settings share the prefix of the networkd files.
+    fn owned(&self) -> Result<Vec<String>, GVMError> {
+        let mut files = listed_files(NETWORKD_DIR, |name| name.starts_with(NETWORKD_PREFIX))?;
+        files.extend(wg_quick_owned()?);
+
+        Ok(files)
+    }
+}
@@ -0,0 +1,153 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This validates the environment the guest program runs in.
+//!
+//! Every check is run even if a previous one failed, so the host gets the full picture of
+//! why a guest is unable to configure itself. The availability of every networking backend
+//! is reported alongside, so a backend detected wrongly, or not at all, can be explained.
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::result::Result;
+
+use crate::common::GVMError;
+use crate::config::Config;
+use crate::linux::networking::{
+    backend_dir, backend_services, backend_tools, detect_backend, service_active, Backend,
+};
+
+/// Directories searched for tools on top of `PATH`, as it often lacks the sbin directories.
+const TOOL_DIRS: [&str; 4] = ["/usr/sbin", "/sbin", "/usr/bin", "/bin"];
+
+/// Outcome of a single check.
+#[derive(Serialize, Debug)]
+pub struct Check {
+    /// Name of the check.
+    pub name: String,
+    /// Whether the check passed.
+    pub passed: bool,
+    /// Reason of the failure.
+    pub error: Option<String>,
+}
+
+/// Availability of a networking backend on the guest.
+#[derive(Serialize, Debug)]
+pub struct BackendReport {
+    /// Backend reported.
+    pub backend: Backend,
+    /// Whether support for the backend was built in.
+    pub built: bool,
+    /// Whether the configuration directory of the backend exists.
+    pub dir: bool,
+    /// Tool applying the configuration -> whether it was found.
+    pub tools: BTreeMap<String, bool>,
+    /// Service applying the configuration -> whether it is active.
+    pub services: BTreeMap<String, bool>,
+    /// Whether the backend is the one detected as in use.
+    pub detected: bool,
+}
+
+/// Outcome of every check.
+#[derive(Serialize, Debug)]
+pub struct SelfTestReport {
+    /// Whether every check passed.
+    pub passed: bool,
+    /// Outcome of the individual checks.
+    pub checks: Vec<Check>,
+    /// Availability of every networking backend, informational only.
+    pub backends: Vec<BackendReport>,
+}
+
+/// Creates the outcome of the check `name` from `res`.
+fn check(name: &str, res: Result<(), String>) -> Check {
+    Check {
+        name: name.to_owned(),
+        passed: res.is_ok(),
+        error: res.err(),
+    }
+}
+
+/// Checks if the executable `name` can be found.
+fn find_tool(name: &str) -> Result<(), String> {
+    let path = env::var("PATH").unwrap_or_default();
+    let found = path
+        .split(':')
+        .chain(TOOL_DIRS)
+        .any(|dir| Path::new(dir).join(name).is_file());
+
+    if !found {
+        return Err(name.to_owned() + " not found");
+    }
+
+    Ok(())
+}
+
+/// Checks if files can be created inside `dir`, by creating and removing a file.
+fn writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(".gvm-guest-selftest");
+
+    fs::write(&probe, "")
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|err| err.to_string())
+}
+
+/// Reports the availability of every networking backend, whether built in or not.
+pub fn backends() -> Vec<BackendReport> {
+    let detected = detect_backend().ok();
+
+    Backend::ALL
+        .into_iter()
+        .map(|backend| BackendReport {
+            backend,
+            built: backend.built(),
+            dir: Path::new(backend_dir(backend)).is_dir(),
+            tools: backend_tools(backend)
+                .into_iter()
+                .map(|tool| (tool.to_owned(), find_tool(tool).is_ok()))
+                .collect(),
+            services: backend_services(backend)
+                .into_iter()
+                .map(|service| (service.to_owned(), service_active(service)))
+                .collect(),
+            detected: detected == Some(backend),
+        })
+        .collect()
+}
+
+/// Runs every check, `comms` is the outcome of sending a message to the host.
+pub fn self_test(config: &Config, comms: Result<(), GVMError>) -> SelfTestReport {
+    let mut checks = vec![check("comms", comms.map_err(|err| err.to_string()))];
+
+    match detect_backend() {
+        Ok(backend) => {
+            checks.push(check("backend", Ok(())));
+            for tool in backend_tools(backend) {
+                checks.push(check(&("tool:".to_owned() + tool), find_tool(tool)));
+            }
+            let dir = backend_dir(backend);
+            checks.push(check(
+                &("writable:".to_owned() + dir),
+                writable(Path::new(dir)),
+            ));
+        }
+        Err(err) => checks.push(check("backend", Err(err.to_string()))),
+    }
+
+    let plugins = fs::read_dir(&config.plugins_dir)
+        .map(|_| ())
+        .map_err(|err| err.to_string());
+    checks.push(check("plugins_dir", plugins));
+
+    if let Some(dir) = config.marker_path.parent() {
+        let name = "writable:".to_owned() + &dir.to_string_lossy();
+        checks.push(check(&name, writable(dir)));
+    }
+
+    SelfTestReport {
+        passed: checks.iter().all(|check| check.passed),
+        checks,
+        backends: backends(),
+    }
+}
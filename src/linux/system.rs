@@ -0,0 +1,216 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This handles system wide actions inside the guest, that are not tied to networking.
+use crate::common::{GVMError, PowerAction, Time};
+use crate::config::Config;
+use crate::files::write_atomic;
+use crate::linux::comms::{read_limit, COMPRESSION_ALGORITHMS};
+use crate::linux::dry_run;
+use crate::linux::networking::{detect_backend, run_privileged_checked, service_active, Backend};
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::result::Result;
+
+/// Checks `hostname` is legal as per RFC 1123, dot separated labels of at most 63 letters,
+/// digits or hyphens, not starting or ending with a hyphen.
+fn valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= 253
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Sets the hostname of the system to `hostname`, both persistently and for the running
+/// system.
+pub fn set_hostname(hostname: &str, config: &Config) -> Result<(), GVMError> {
+    if !valid_hostname(hostname) {
+        return Err(GVMError::InvalidHostname(hostname.to_owned()));
+    }
+
+    info!("Setting hostname: {}", hostname);
+    if dry_run() {
+        return Ok(());
+    }
+    fs::write("/etc/hostname", hostname.to_owned() + "\n")?;
+    run_privileged_checked(config, &["hostnamectl", "set-hostname", hostname])
+}
+
+/// Directory of the zoneinfo database, holding a file per timezone.
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+/// Drop-in holding the NTP servers of systemd-timesyncd.
+const TIMESYNCD_DROP_IN: &str = "/etc/systemd/timesyncd.conf.d/90-gvm.conf";
+
+/// Drop-in directories of chrony, Debian based distributions first.
+const CHRONY_DIRS: [&str; 2] = ["/etc/chrony/conf.d", "/etc/chrony.d"];
+
+/// Checks `timezone` names a timezone of the zoneinfo database, without escaping it.
+fn valid_timezone(timezone: &str) -> bool {
+    !timezone.is_empty()
+        && timezone.split('/').all(|part| {
+            !part.is_empty()
+                && part != "."
+                && part != ".."
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-+".contains(c))
+        })
+        && Path::new(ZONEINFO_DIR).join(timezone).is_file()
+}
+
+/// Sets the timezone and the NTP servers of the system according to `time`. The NTP
+/// servers go to chrony when it runs, to systemd-timesyncd otherwise.
+pub fn set_time(time: &Time, config: &Config) -> Result<(), GVMError> {
+    if let Some(timezone) = &time.timezone {
+        if !valid_timezone(timezone) {
+            return Err(GVMError::InvalidTime(timezone.clone()));
+        }
+    }
+    let servers = time.ntp.as_deref().unwrap_or_default();
+    for server in servers {
+        if server.parse::<IpAddr>().is_err() && !valid_hostname(server) {
+            return Err(GVMError::InvalidTime(server.clone()));
+        }
+    }
+
+    info!("Setting time: {:?}", time);
+    if dry_run() {
+        return Ok(());
+    }
+
+    if let Some(timezone) = &time.timezone {
+        run_privileged_checked(config, &["timedatectl", "set-timezone", timezone])?;
+    }
+    if time.ntp.is_none() {
+        return Ok(());
+    }
+
+    let chrony = ["chronyd", "chrony"]
+        .into_iter()
+        .find(|service| service_active(service));
+    match chrony {
+        Some(service) => {
+            let Some(dir) = CHRONY_DIRS.into_iter().find(|dir| Path::new(dir).is_dir()) else {
+                warn!("No drop-in directory found for chrony");
+                return Err(GVMError::IOError);
+            };
+            let contents: String = servers
+                .iter()
+                .map(|server| "server ".to_owned() + server + " iburst\n")
+                .collect();
+            write_atomic(&Path::new(dir).join("90-gvm.conf"), &contents, 0o644)?;
+            run_privileged_checked(config, &["systemctl", "restart", service])?;
+        }
+        None => {
+            let contents = "".to_owned() + "[Time]\n" + "NTP=" + &servers.join(" ") + "\n";
+            fs::create_dir_all("/etc/systemd/timesyncd.conf.d")?;
+            write_atomic(Path::new(TIMESYNCD_DROP_IN), &contents, 0o644)?;
+            run_privileged_checked(config, &["systemctl", "restart", "systemd-timesyncd"])?;
+            run_privileged_checked(config, &["timedatectl", "set-ntp", "true"])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Powers off or reboots the system according to `action`.
+pub fn power(action: PowerAction, config: &Config) -> Result<(), GVMError> {
+    let arg = match action {
+        PowerAction::Poweroff => "poweroff",
+        PowerAction::Reboot => "reboot",
+    };
+    if dry_run() {
+        info!("Would run systemctl {}", arg);
+        return Ok(());
+    }
+
+    run_privileged_checked(config, &["systemctl", arg])
+}
+
+/// Facts about the guest, letting the host tailor the commands it sends.
+#[derive(Serialize, Debug)]
+pub struct Facts {
+    /// Name of the distribution, ie. Ubuntu.
+    pub os_name: Option<String>,
+    /// Version of the distribution, ie. 22.04.
+    pub os_version: Option<String>,
+    /// Release of the running kernel.
+    pub kernel: Option<String>,
+    /// Architecture the guest program was built for, ie. x86_64.
+    pub arch: &'static str,
+    /// Networking backend configured by the guest, absent if it can't be determined.
+    pub backend: Option<Backend>,
+}
+
+/// Reads the value of `key` from the os-release `contents`, without its quotes.
+fn os_release_value(contents: &str, key: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let (name, value) = line.split_once('=')?;
+        (name.trim() == key).then(|| value.trim().trim_matches('"').to_owned())
+    })
+}
+
+/// Gathers the facts about the guest, only reading files so it is cheap to call.
+pub fn facts() -> Facts {
+    let os_release = fs::read_to_string("/etc/os-release").unwrap_or_default();
+    let kernel = fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|release| release.trim().to_owned());
+
+    Facts {
+        os_name: os_release_value(&os_release, "NAME"),
+        os_version: os_release_value(&os_release, "VERSION_ID"),
+        kernel,
+        arch: env::consts::ARCH,
+        backend: detect_backend().ok(),
+    }
+}
+
+/// Features of the guest program the host can rely on.
+#[derive(Serialize, Debug)]
+pub struct Capabilities {
+    /// Compression algorithms offered to [crate::common::GVMCmd::Negotiate].
+    pub compression: &'static [&'static str],
+    /// Whether plugins are supported.
+    pub plugins: bool,
+    /// Networking backends built in.
+    pub backends: Vec<Backend>,
+    /// Longest message accepted from the host, in bytes.
+    pub max_message_bytes: usize,
+}
+
+/// Announcement sent to the host once the line to it is open.
+#[derive(Serialize, Debug)]
+pub struct Hello {
+    /// Version of the guest program.
+    pub version: &'static str,
+    /// Facts about the guest.
+    pub facts: Facts,
+    /// Features of the guest program.
+    pub capabilities: Capabilities,
+}
+
+/// Describes the guest program and the guest, for the host.
+pub fn hello() -> Hello {
+    Hello {
+        version: env!("CARGO_PKG_VERSION"),
+        facts: facts(),
+        capabilities: Capabilities {
+            compression: COMPRESSION_ALGORITHMS,
+            plugins: cfg!(feature = "plugins"),
+            backends: Backend::ALL
+                .into_iter()
+                .filter(|backend| backend.built())
+                .collect(),
+            max_message_bytes: read_limit(),
+        },
+    }
+}
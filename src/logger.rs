@@ -0,0 +1,47 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This is the logger of the GVM guest program, writing every record at or above the
+//! configured level to stdout, where the init system collects it.
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::OnceLock;
+
+/// Logger writing records to stdout.
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StdoutLogger = StdoutLogger;
+
+/// Level given on the command line to [init], taking precedence over the configured one.
+static COMMAND_LINE_LEVEL: OnceLock<Option<LevelFilter>> = OnceLock::new();
+
+/// Level logged at when neither the command line nor the configuration set one.
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Installs the logger, only logging records at or above the `level` of the command line,
+/// or at or above info without one.
+pub fn init(level: Option<LevelFilter>) {
+    if log::set_logger(&LOGGER).is_ok() {
+        let _ = COMMAND_LINE_LEVEL.set(level);
+        log::set_max_level(level.unwrap_or(DEFAULT_LEVEL));
+    }
+}
+
+/// Only logs records at or above the `configured` level from now on, unless the command
+/// line set one.
+pub fn set_level(configured: Option<LevelFilter>) {
+    let command_line = COMMAND_LINE_LEVEL.get().copied().flatten();
+    log::set_max_level(command_line.or(configured).unwrap_or(DEFAULT_LEVEL));
+}
@@ -0,0 +1,841 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This handles the lifecycle of the shared library plugins loaded into the guest.
+//!
+//! Plugins are discovered inside the plugin directory at startup and registered by their
+//! short name (the file name without the `.so` extension). The host may refer to a plugin
+//! either by that short name, or by an absolute path for backwards compatibility.
+//!
+//! Every symbol of the plugin API is optional, a plugin missing one still loads and answers
+//! the matching command with [GVMError::PluginCommandNotSupported].
+//!
+//! A plugin can be loaded several times under different instance names, each instance
+//! getting its own copy of the library, and so its own state. Commands pick the instance
+//! by name, a plugin loaded without one is its own instance, named after its path.
+//!
+//! Plugins exporting `set_log_callback` are handed a callback when loaded, logging through
+//! it tags every line with the short name of the plugin.
+//!
+//! The host can install plugins into the plugin directory, sending them as numbered base64
+//! chunks.
+//!
+//! Plugins slow to initialize can be started on a thread of their own, the host polling
+//! their [StartState] while the guest program keeps answering other commands. A plugin
+//! still starting refuses commands with [GVMError::PluginStarting].
+//!
+//! Starting a plugin can change the working directory and lower the resource limits of the
+//! guest program, which the plugin shares, so a misbehaving plugin is somewhat contained
+//! while it starts. Both are restored once the plugin is done starting.
+//!
+//! Both installing and loading a plugin can carry a SHA-256 checksum of the plugin. Once a
+//! plugin public key is configured, they also need an ed25519 signature of the plugin from
+//! the matching private key, giving a chain of trust for the code running in the guest. A
+//! plugin failing either check is refused with [GVMError::PluginIntegrity] before it is
+//! written or loaded. Plugins are loaded from a sealed copy in memory of the bytes checked,
+//! so the file can't be swapped between the checks and the load.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use dlopen::raw::Library;
+use dlopen::wrapper::{OptionalContainer, WrapperApi};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::fd::FromRawFd;
+use std::os::raw::{c_char, c_void};
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::common::{valid_env_name, GVMError, PluginLimits};
+use crate::files::write_atomic;
+use crate::linux::dry_run;
+
+/// Largest plugin the host can install.
+const MAX_PLUGIN_SIZE: usize = 64 * 1024 * 1024;
+
+/// This API is exposed by shared library files on the guest in question.
+/// We use this api to expose additional, potentially proprietary guest specific
+/// APIs. Symbols missing from the plugin are `None`.
+pub struct PluginApi {
+    /// Plugin initialization code, it creates a persistent state in the library.
+    ///
+    /// NOTE: The return is copied and NOT freed, it MUST be a static string or a buffer owned
+    /// by the plugin, or NULL for no response.
+    start: Option<unsafe extern "C" fn() -> *const c_char>,
+    /// Processes a command through the plugin API, answering with a JSON object.
+    ///
+    /// NOTE: The return is copied and NOT freed, it MUST be a static string or a buffer owned
+    /// by the plugin, or NULL for no response. An allocated string is leaked.
+    cmd_process: Option<unsafe extern "C" fn(msg: *const c_char) -> *const c_char>,
+    /// Shuts down the persistent state in the library.
+    ///
+    /// NOTE: The return is copied and NOT freed, it MUST be a static string or a buffer owned
+    /// by the plugin, or NULL for no response.
+    stop: Option<unsafe extern "C" fn() -> *const c_char>,
+}
+
+/// Loads the symbol `name` of type `T` from `lib`, `None` if the library lacks it.
+unsafe fn optional_symbol<T>(lib: &Library, name: &CStr) -> Result<Option<T>, dlopen::Error> {
+    match lib.symbol_cstr(name) {
+        Ok(symbol) => Ok(Some(symbol)),
+        Err(dlopen::Error::SymbolGettingError(_) | dlopen::Error::NullSymbol) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// Written by hand, as the derive makes every symbol mandatory.
+impl WrapperApi for PluginApi {
+    unsafe fn load(lib: &Library) -> Result<PluginApi, dlopen::Error> {
+        Ok(PluginApi {
+            start: optional_symbol(lib, c"start")?,
+            cmd_process: optional_symbol(lib, c"cmd_process")?,
+            stop: optional_symbol(lib, c"stop")?,
+        })
+    }
+}
+
+/// Callback handed to plugins to log `msg`, `ctx` is the context registered alongside it.
+pub type LogCallback = extern "C" fn(ctx: *const c_void, msg: *const c_char);
+
+/// Optional API exposed by plugins routing their logs through the guest program, rather
+/// than writing to the shared stdout where they can't be told apart.
+#[derive(WrapperApi)]
+pub struct LogApi {
+    /// Registers the `callback` the plugin logs through, `ctx` must be passed back to it on
+    /// every call.
+    set_log_callback: unsafe extern "C" fn(callback: LogCallback, ctx: *const c_void),
+}
+
+/// Progress of the start of a plugin, reported to the host as `{"state": "<state>",
+/// "resp": <resp>}`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "state", content = "resp")]
+pub enum StartState {
+    /// The plugin was loaded but never started.
+    NotStarted,
+    /// The plugin is starting in the background.
+    Starting,
+    /// The plugin started, carries the response of its start.
+    Ready(Option<String>),
+    /// The plugin failed to start, carries the reason.
+    Failed(String),
+}
+
+/// Plugin loaded into the guest.
+struct Plugin {
+    /// Symbols of the plugin.
+    api: OptionalContainer<PluginApi, LogApi>,
+    /// Memory file the plugin was loaded from. Kept open while the plugin is loaded, after
+    /// `api` is dropped, so its `/proc/self/fd` path can't name another plugin for the
+    /// dynamic loader, which matches loaded libraries by path.
+    _memfd: File,
+    /// Path the plugin was loaded from.
+    path: String,
+    /// Name of the plugin tagging its logs, handed to the plugin as the log context so it
+    /// needs to outlive the library.
+    _log_name: CString,
+    /// Limits applied when the plugin was last started.
+    limits: Option<PluginLimits>,
+    /// Progress of the last start of the plugin, updated by `starter`.
+    state: Arc<Mutex<StartState>>,
+    /// Thread starting the plugin in the background, if any.
+    starter: Option<JoinHandle<()>>,
+}
+
+impl Plugin {
+    /// Provides the progress of the last start of the plugin. A start thread which ended
+    /// without recording its result died along the way.
+    fn state(&self) -> StartState {
+        let state = self
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone();
+        match state {
+            StartState::Starting
+                if self
+                    .starter
+                    .as_ref()
+                    .is_some_and(|starter| starter.is_finished()) =>
+            {
+                StartState::Failed("start thread died".to_owned())
+            }
+            state => state,
+        }
+    }
+
+    /// Waits for the plugin to be done starting in the background, if it is.
+    fn join(&mut self) {
+        if let Some(starter) = self.starter.take() {
+            let _ = starter.join();
+        }
+    }
+}
+
+/// Plugin being received from the host.
+struct Upload {
+    /// Chunks received so far, decoded.
+    data: Vec<u8>,
+    /// Position of the next expected chunk.
+    next: u32,
+}
+
+/// Parses the hex encoded ed25519 public key `key`.
+fn parse_public_key(key: &str) -> Result<VerifyingKey, GVMError> {
+    let bytes: [u8; 32] = hex::decode(key.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(GVMError::InvalidConfig)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| GVMError::InvalidConfig)
+}
+
+/// Checks the plugin `data` against the hex encoded SHA-256 `checksum` and the hex encoded
+/// ed25519 `signature` from `key`, each check is skipped when its value is absent. Returns
+/// the SHA-256 of `data`.
+fn verify(
+    data: &[u8],
+    checksum: Option<&str>,
+    signature: Option<&str>,
+    key: Option<&VerifyingKey>,
+) -> Result<String, GVMError> {
+    let digest = hex::encode(Sha256::digest(data));
+    if let Some(checksum) = checksum {
+        if !checksum.eq_ignore_ascii_case(&digest) {
+            return Err(GVMError::PluginIntegrity(
+                "checksum mismatch: ".to_owned() + &digest,
+            ));
+        }
+    }
+
+    if let Some(key) = key {
+        let signature =
+            signature.ok_or_else(|| GVMError::PluginIntegrity("missing signature".to_owned()))?;
+        let signature: [u8; 64] = hex::decode(signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| GVMError::PluginIntegrity("invalid signature".to_owned()))?;
+        key.verify(data, &Signature::from_bytes(&signature))
+            .map_err(|_| GVMError::PluginIntegrity("bad signature".to_owned()))?;
+    }
+
+    Ok(digest)
+}
+
+/// Loads the library made of `data`, named `name` for debugging, from a sealed memory file
+/// holding exactly these bytes. Nothing else can swap or change the library between the
+/// checks of `data` and its load, and every load gets its own copy of the library, with
+/// its own state. Returns the memory file, which has to outlive the library.
+fn load_memfd(
+    data: &[u8],
+    name: &CStr,
+) -> Result<(OptionalContainer<PluginApi, LogApi>, File), GVMError> {
+    let fd =
+        unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(data)?;
+
+    let seals = libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+    if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let api = unsafe { OptionalContainer::load(format!("/proc/self/fd/{}", fd)) }
+        .map_err(|err| GVMError::PluginLoadFailed(err.to_string()))?;
+    Ok((api, file))
+}
+
+/// Checks if `name` can be used as the file name of a plugin.
+fn valid_plugin_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Keeps track of the plugins available on the guest, and the ones currently loaded.
+pub struct PluginManager {
+    /// Directory scanned for plugins.
+    dir: PathBuf,
+    /// Short name -> path of every plugin discovered inside `dir`.
+    available: HashMap<String, PathBuf>,
+    /// Instance name, or path of the plugins loaded without one -> loaded plugin.
+    loaded: HashMap<String, Plugin>,
+    /// Maximum number of plugins loaded at the same time.
+    max_loaded: usize,
+    /// Short name -> plugin being installed by the host.
+    uploads: HashMap<String, Upload>,
+    /// Key every plugin has to be signed with, when present.
+    public_key: Option<VerifyingKey>,
+}
+
+/// Copies a string returned by a plugin into an owned string.
+fn plugin_str(c_buf: *const c_char) -> Option<String> {
+    if c_buf.is_null() {
+        return None;
+    }
+
+    let c_str: &CStr = unsafe { CStr::from_ptr(c_buf) };
+    Some(c_str.to_string_lossy().into_owned())
+}
+
+/// Logs `msg` from the plugin named by `ctx`.
+extern "C" fn plugin_log(ctx: *const c_void, msg: *const c_char) {
+    let name = plugin_str(ctx as *const c_char).unwrap_or_default();
+
+    if let Some(msg) = plugin_str(msg) {
+        info!("[plugin {}] {}", name, msg.trim_end());
+    }
+}
+
+/// Sets the environment variables `env`, none are set if any of them is invalid. The values
+/// are never logged, as they may hold secrets.
+///
+/// The environment belongs to the whole guest program, and the variables remain set for
+/// every plugin started afterwards. Changing it isn't synchronized with C code reading it
+/// from other threads, ie. plugins starting in the background or calling getenv from threads
+/// of their own, so hosts should only pass `env` before starting any plugin in the
+/// background.
+fn set_env(env: &HashMap<String, String>) -> Result<(), GVMError> {
+    for (name, value) in env {
+        if !valid_env_name(name) {
+            return Err(GVMError::InvalidEnvName(name.clone()));
+        } else if value.contains('\0') {
+            return Err(GVMError::InvalidMessage);
+        }
+    }
+
+    for (name, value) in env {
+        info!("Setting environment variable {}", name);
+        env::set_var(name, value);
+    }
+
+    Ok(())
+}
+
+/// Working directory and resource limits of the guest program replaced by [set_limits], put
+/// back once dropped.
+struct SavedLimits {
+    /// Working directory, if it was changed.
+    dir: Option<PathBuf>,
+    /// Resource limits that were lowered, with their previous values.
+    resources: Vec<(libc::__rlimit_resource_t, libc::rlimit)>,
+}
+
+impl Drop for SavedLimits {
+    /// Failures are only logged, the plugin having already started.
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            if let Err(err) = env::set_current_dir(dir) {
+                error!("Failed to restore working directory {:?}: {}", dir, err);
+            }
+        }
+        for (resource, rlim) in &self.resources {
+            if unsafe { libc::setrlimit(*resource, rlim) } != 0 {
+                error!(
+                    "Failed to restore resource limit {}: {}",
+                    resource,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    }
+}
+
+/// Changes the working directory and lowers the soft resource limits of the guest program
+/// to `limits`, returning what was replaced so it can be restored. Hard limits are left
+/// alone, so the soft limits can be raised back up.
+fn set_limits(limits: &PluginLimits) -> Result<SavedLimits, GVMError> {
+    let mut saved = SavedLimits {
+        dir: None,
+        resources: Vec::new(),
+    };
+    if let Some(dir) = &limits.dir {
+        info!("Changing working directory to {}", dir);
+        let current = env::current_dir()?;
+        env::set_current_dir(dir)?;
+        saved.dir = Some(current);
+    }
+
+    let resources = [
+        (libc::RLIMIT_CPU, limits.cpu),
+        (libc::RLIMIT_AS, limits.memory),
+        (libc::RLIMIT_NOFILE, limits.files),
+    ];
+    for (resource, limit) in resources {
+        let Some(limit) = limit else { continue };
+
+        let mut rlim = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(resource, &mut rlim) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let previous = rlim;
+        rlim.rlim_cur = limit.min(rlim.rlim_max);
+        if unsafe { libc::setrlimit(resource, &rlim) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        saved.resources.push((resource, previous));
+    }
+
+    info!("Applied plugin limits {:?}", limits);
+    Ok(saved)
+}
+
+impl PluginManager {
+    /// Creates a plugin manager loading at most `max_loaded` plugins, and discovers the
+    /// plugins inside of `dir`. When given, every plugin has to be signed with the hex
+    /// encoded ed25519 `public_key`, which fails with [GVMError::InvalidConfig] if invalid.
+    pub fn new(
+        dir: &Path,
+        max_loaded: usize,
+        public_key: Option<&str>,
+    ) -> Result<PluginManager, GVMError> {
+        let public_key = match public_key {
+            Some(key) => Some(parse_public_key(key).inspect_err(|_| {
+                error!("Invalid plugin public key, expected 32 hex encoded bytes");
+            })?),
+            None => None,
+        };
+
+        let mut manager = PluginManager {
+            dir: dir.to_path_buf(),
+            available: HashMap::new(),
+            loaded: HashMap::new(),
+            max_loaded,
+            uploads: HashMap::new(),
+            public_key,
+        };
+        manager.discover();
+        Ok(manager)
+    }
+
+    /// Rescans the plugin directory for `.so` files. A missing directory simply means no
+    /// plugins are available.
+    pub fn discover(&mut self) {
+        self.available.clear();
+
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("so") {
+                continue;
+            }
+
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                info!("Discovered plugin: {} -> {}", name, path.display());
+                self.available.insert(name.to_owned(), path.clone());
+            }
+        }
+    }
+
+    /// Returns the short names of all discovered plugins.
+    pub fn available(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.available.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the number of loaded plugins.
+    pub fn loaded_count(&self) -> usize {
+        self.loaded.len()
+    }
+
+    /// Returns the limits applied when starting each loaded plugin, keyed by instance.
+    pub fn limits(&self) -> BTreeMap<String, PluginLimits> {
+        self.loaded
+            .iter()
+            .filter_map(|(path, plugin)| Some((path.clone(), plugin.limits.clone()?)))
+            .collect()
+    }
+
+    /// Changes the maximum number of loaded plugins to `max_loaded`. Plugins already loaded
+    /// stay loaded, even above the new limit.
+    pub fn set_max_loaded(&mut self, max_loaded: usize) {
+        self.max_loaded = max_loaded;
+    }
+
+    /// Resolves `name` into a plugin path, either through a discovered short name, or by
+    /// treating it as a path.
+    fn resolve(&self, name: &str) -> String {
+        match self.available.get(name) {
+            Some(path) => path.to_string_lossy().into_owned(),
+            None => name.to_owned(),
+        }
+    }
+
+    /// Provides the key of the plugin `name` loaded as `instance` inside the loaded plugins,
+    /// the path of the plugin when loaded without an instance name.
+    fn key(&self, name: &str, instance: Option<&str>) -> String {
+        match instance {
+            Some(instance) => instance.to_owned(),
+            None => self.resolve(name),
+        }
+    }
+
+    /// Gets the plugin `name` loaded as `instance`, refusing it with
+    /// [GVMError::PluginStarting] while it starts in the background.
+    fn get(
+        &self,
+        name: &str,
+        instance: Option<&str>,
+    ) -> Result<&OptionalContainer<PluginApi, LogApi>, GVMError> {
+        let plugin = self
+            .loaded
+            .get(&self.key(name, instance))
+            .ok_or(GVMError::PluginNotFound)?;
+        if matches!(plugin.state(), StartState::Starting) {
+            return Err(GVMError::PluginStarting);
+        }
+        Ok(&plugin.api)
+    }
+
+    /// Provides the progress of the start of the plugin `name` loaded as `instance`.
+    pub fn start_state(&self, name: &str, instance: Option<&str>) -> Result<StartState, GVMError> {
+        self.loaded
+            .get(&self.key(name, instance))
+            .map(Plugin::state)
+            .ok_or(GVMError::PluginNotFound)
+    }
+
+    /// Loads the plugin `name` into the guest as `instance`, this needs to be done before
+    /// starting it. The plugin is checked against `checksum` and `signature` beforehand, see
+    /// [verify].
+    pub fn create_links(
+        &mut self,
+        name: &str,
+        instance: Option<&str>,
+        checksum: Option<&str>,
+        signature: Option<&str>,
+    ) -> Result<(), GVMError> {
+        if let Some(instance) = instance.filter(|instance| !valid_plugin_name(instance)) {
+            return Err(GVMError::InvalidPluginName(instance.to_owned()));
+        }
+
+        let path = self.resolve(name);
+        let key = self.key(name, instance);
+
+        if self.loaded.contains_key(&key) {
+            return Err(GVMError::PluginLoaded);
+        }
+
+        if !Path::new(&path).exists() {
+            return Err(GVMError::PluginNotFound);
+        }
+
+        if self.loaded.len() >= self.max_loaded {
+            return Err(GVMError::PluginLimitReached);
+        }
+
+        let stem = Path::new(&path).file_stem().unwrap_or_default();
+        let log_name = instance.map_or_else(|| stem.to_string_lossy(), |instance| instance.into());
+        let log_name = CString::new(log_name.as_bytes()).unwrap_or_default();
+
+        // Loaded from the very bytes checked, rather than from the path again, which could
+        // have been replaced in between.
+        let data = fs::read(&path)?;
+        verify(&data, checksum, signature, self.public_key.as_ref()).inspect_err(|err| {
+            warn!("Refusing to load plugin {}: {:?}", path, err);
+        })?;
+        let (api, memfd) = load_memfd(&data, &log_name)?;
+        let missing: Vec<&str> = [
+            ("start", api.start.is_none()),
+            ("cmd_process", api.cmd_process.is_none()),
+            ("stop", api.stop.is_none()),
+        ]
+        .into_iter()
+        .filter_map(|(symbol, missing)| missing.then_some(symbol))
+        .collect();
+        if !missing.is_empty() {
+            info!("Plugin {} does not implement {:?}", path, missing);
+        }
+
+        if let Some(log) = api.optional() {
+            unsafe { log.set_log_callback(plugin_log, log_name.as_ptr() as *const c_void) };
+        }
+        self.loaded.insert(
+            key,
+            Plugin {
+                api,
+                _memfd: memfd,
+                path,
+                _log_name: log_name,
+                limits: None,
+                state: Arc::new(Mutex::new(StartState::NotStarted)),
+                starter: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Starts the plugin `name` loaded as `instance`, after setting the environment variables
+    /// `env` and applying `limits`, see [set_limits]. Plugins share the environment of the
+    /// guest program, so it remains for every plugin started afterwards, while the working
+    /// directory and resource limits are restored once the plugin is done starting.
+    ///
+    /// In the `background`, the plugin starts on a thread of its own and `Starting` is
+    /// answered right away, see [PluginManager::start_state]. The limits then stay applied
+    /// to the whole guest program until the thread is done.
+    pub fn start(
+        &mut self,
+        name: &str,
+        instance: Option<&str>,
+        env: Option<&HashMap<String, String>>,
+        limits: Option<&PluginLimits>,
+        background: bool,
+    ) -> Result<Option<String>, GVMError> {
+        let key = self.key(name, instance);
+        let plugin = self.loaded.get_mut(&key).ok_or(GVMError::PluginNotFound)?;
+        if matches!(plugin.state(), StartState::Starting) {
+            return Err(GVMError::PluginStarting);
+        }
+        plugin.join();
+        let start = plugin
+            .api
+            .start
+            .ok_or(GVMError::PluginCommandNotSupported)?;
+        if let Some(env) = env {
+            set_env(env)?;
+        }
+        let saved = match limits {
+            Some(limits) => {
+                let saved = set_limits(limits)?;
+                plugin.limits = Some(limits.clone());
+                Some(saved)
+            }
+            None => None,
+        };
+
+        if !background {
+            let resp = plugin_str(unsafe { start() });
+            drop(saved);
+            *plugin.state.lock().unwrap_or_else(|err| err.into_inner()) =
+                StartState::Ready(resp.clone());
+            return Ok(resp);
+        }
+
+        // The library stays loaded until the thread is joined, see [PluginManager::stop_all].
+        *plugin.state.lock().unwrap_or_else(|err| err.into_inner()) = StartState::Starting;
+        let state = Arc::clone(&plugin.state);
+        let starter = thread::Builder::new()
+            .name("plugin-start".to_owned())
+            .spawn(move || {
+                let resp = plugin_str(unsafe { start() });
+                drop(saved);
+                info!("Plugin started in the background: {:?}", resp);
+                *state.lock().unwrap_or_else(|err| err.into_inner()) = StartState::Ready(resp);
+            });
+        match starter {
+            Ok(starter) => plugin.starter = Some(starter),
+            Err(err) => {
+                *plugin.state.lock().unwrap_or_else(|err| err.into_inner()) =
+                    StartState::Failed(err.to_string());
+                return Err(err.into());
+            }
+        }
+
+        Ok(Some("Starting".to_owned()))
+    }
+
+    /// Forwards `msg` to the plugin `name` loaded as `instance`. Plugins answer with a JSON
+    /// object, anything else is refused with [GVMError::PluginBadResponse] rather than
+    /// forwarded to the host.
+    pub fn cmd_process(
+        &self,
+        name: &str,
+        instance: Option<&str>,
+        msg: String,
+    ) -> Result<Option<Value>, GVMError> {
+        let plugin = self.get(name, instance)?;
+        let cstr = CString::new(msg).map_err(|_| GVMError::InvalidMessage)?;
+        let cmd_process = plugin
+            .cmd_process
+            .ok_or(GVMError::PluginCommandNotSupported)?;
+        let resp = match plugin_str(unsafe { cmd_process(cstr.as_ptr()) }) {
+            Some(resp) => resp,
+            None => return Ok(None),
+        };
+
+        match serde_json::from_str::<Value>(&resp) {
+            Ok(value) if value.is_object() => Ok(Some(value)),
+            Ok(_) => Err(GVMError::PluginBadResponse("not an object".to_owned())),
+            Err(err) => {
+                warn!("Plugin {} answered with invalid JSON: {:?}", name, resp);
+                Err(GVMError::PluginBadResponse(err.to_string()))
+            }
+        }
+    }
+
+    /// Receives the chunk at position `seq` of the plugin `name`, encoded as base64. Chunks
+    /// have to arrive in order, any error drops the chunks received so far. Once `finished`
+    /// the plugin is checked against `checksum` and `signature`, see [verify], and written to
+    /// the plugin directory, where it replaces any plugin of the same name which isn't
+    /// loaded.
+    pub fn install(
+        &mut self,
+        name: &str,
+        chunk: &str,
+        seq: u32,
+        finished: bool,
+        checksum: Option<&str>,
+        signature: Option<&str>,
+    ) -> Result<(), GVMError> {
+        if !valid_plugin_name(name) {
+            return Err(GVMError::InvalidPluginName(name.to_owned()));
+        }
+
+        let path = self.dir.join(name.to_owned() + ".so");
+        if self
+            .loaded
+            .values()
+            .any(|plugin| Path::new(&plugin.path) == path)
+        {
+            self.uploads.remove(name);
+            warn!("Refusing to replace loaded plugin {}", name);
+            return Err(GVMError::PluginLoaded);
+        }
+
+        let mut upload = match self.uploads.remove(name) {
+            Some(upload) if upload.next == seq => upload,
+            None if seq == 0 => Upload {
+                data: Vec::new(),
+                next: 0,
+            },
+            _ => {
+                warn!("Plugin {} chunk {} is out of order", name, seq);
+                return Err(GVMError::InvalidMessage);
+            }
+        };
+
+        let chunk = STANDARD
+            .decode(chunk)
+            .map_err(|_| GVMError::InvalidMessage)?;
+        if upload.data.len() + chunk.len() > MAX_PLUGIN_SIZE {
+            warn!("Plugin {} is larger than {} bytes", name, MAX_PLUGIN_SIZE);
+            return Err(GVMError::InvalidMessage);
+        }
+        upload.data.extend_from_slice(&chunk);
+        upload.next += 1;
+
+        if !finished {
+            self.uploads.insert(name.to_owned(), upload);
+            return Ok(());
+        }
+
+        let digest = verify(&upload.data, checksum, signature, self.public_key.as_ref())
+            .inspect_err(|err| warn!("Refusing to install plugin {}: {:?}", name, err))?;
+
+        if dry_run() {
+            info!("Would install plugin {} -> {}", name, path.display());
+            return Ok(());
+        }
+
+        // Written next to the plugin and renamed over it, so a failed install never leaves
+        // a truncated plugin behind.
+        fs::create_dir_all(&self.dir)?;
+        write_atomic(&path, &upload.data, 0o700)?;
+
+        info!(
+            "Installed plugin {} -> {} ({})",
+            name,
+            path.display(),
+            digest
+        );
+        self.discover();
+        Ok(())
+    }
+
+    /// Stops and unloads every loaded plugin, once the ones starting in the background are
+    /// done starting.
+    pub fn stop_all(&mut self) {
+        for (key, mut plugin) in self.loaded.drain() {
+            plugin.join();
+            let resp = plugin
+                .api
+                .stop
+                .and_then(|stop| plugin_str(unsafe { stop() }));
+            info!("Stopped plugin {}: {:?}", key, resp);
+        }
+    }
+
+    /// Stops and unloads the plugin `name` loaded as `instance`, it has to be loaded again
+    /// before being started again. A plugin without a stop hook is only unloaded.
+    pub fn stop(&mut self, name: &str, instance: Option<&str>) -> Result<Option<String>, GVMError> {
+        // Refuses plugins still starting, rather than waiting on them.
+        self.get(name, instance)?;
+
+        let key = self.key(name, instance);
+        let Some(mut plugin) = self.loaded.remove(&key) else {
+            return Err(GVMError::PluginNotFound);
+        };
+        plugin.join();
+        let resp = plugin
+            .api
+            .stop
+            .and_then(|stop| plugin_str(unsafe { stop() }));
+        info!("Stopped plugin {}: {:?}", key, resp);
+        Ok(resp)
+    }
+}
+
+// Plugins still loaded when the manager goes away, ie. when the guest program exits through
+// an error, get an orderly shutdown instead of being unloaded underneath their state.
+impl Drop for PluginManager {
+    fn drop(&mut self) {
+        self.stop_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soft_limit(resource: libc::__rlimit_resource_t) -> libc::rlim_t {
+        let mut rlim = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(unsafe { libc::getrlimit(resource, &mut rlim) }, 0);
+        rlim.rlim_cur
+    }
+
+    #[test]
+    fn limits_restored_after_start() {
+        let dir = env::current_dir().unwrap();
+        let files = soft_limit(libc::RLIMIT_NOFILE);
+        let limits = PluginLimits {
+            dir: Some(env::temp_dir().to_string_lossy().into_owned()),
+            cpu: None,
+            memory: None,
+            files: Some(files - 1),
+        };
+
+        let saved = set_limits(&limits).unwrap();
+        assert_eq!(soft_limit(libc::RLIMIT_NOFILE), files - 1);
+        assert_eq!(
+            env::current_dir().unwrap(),
+            env::temp_dir().canonicalize().unwrap()
+        );
+
+        drop(saved);
+        assert_eq!(soft_limit(libc::RLIMIT_NOFILE), files);
+        assert_eq!(env::current_dir().unwrap(), dir);
+    }
+}
@@ -0,0 +1,122 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This keeps track of the commands recently received from the host, so a command the
+//! transport delivers twice, ie. after a reconnect, is only executed once.
+//!
+//! Commands are told apart by their correlation ID, commands without one are never
+//! considered duplicates.
+use std::collections::{HashMap, VecDeque};
+
+use crate::common::GVMCmd;
+
+/// Number of command IDs remembered, the oldest ones are forgotten first.
+const REPLAY_CAPACITY: usize = 256;
+
+/// Final reply sent for a command, resent as is when the command is delivered again.
+#[derive(Debug, Clone)]
+pub struct CachedReply {
+    /// Command answered.
+    pub cmd: GVMCmd,
+    /// Response of the command.
+    pub resp: Option<String>,
+    /// Structured response of the command.
+    pub data: Option<serde_json::Value>,
+    /// Whether the command succeeded.
+    pub finished: bool,
+}
+
+/// What to do with a command from the host.
+#[derive(Debug)]
+pub enum Delivery {
+    /// First delivery of the command, it has to be executed.
+    New,
+    /// The command is still being executed, ie. a log stream, the duplicate is dropped.
+    Running,
+    /// The command was already executed, its reply is resent instead.
+    Done(CachedReply),
+}
+
+/// Commands recently received from the host, keyed by ID.
+#[derive(Default)]
+pub struct ReplayCache {
+    /// Reply of every remembered command, `None` while it runs.
+    replies: HashMap<String, Option<CachedReply>>,
+    /// Remembered IDs, oldest first.
+    order: VecDeque<String>,
+}
+
+impl ReplayCache {
+    /// Starts tracking the commands of the host.
+    pub fn new() -> ReplayCache {
+        ReplayCache::default()
+    }
+
+    /// Records the delivery of the command `id`, telling whether it has to be executed.
+    pub fn deliver(&mut self, id: &str) -> Delivery {
+        match self.replies.get(id) {
+            Some(Some(reply)) => return Delivery::Done(reply.clone()),
+            Some(None) => return Delivery::Running,
+            None => {}
+        }
+
+        if self.order.len() >= REPLAY_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+        self.order.push_back(id.to_owned());
+        self.replies.insert(id.to_owned(), None);
+        Delivery::New
+    }
+
+    /// Records the `reply` of the command `id`, if it is still remembered.
+    pub fn finish(&mut self, id: &str, reply: CachedReply) {
+        if let Some(cached) = self.replies.get_mut(id) {
+            *cached = Some(reply);
+        }
+    }
+
+    /// Forgets the command `id`, so it is executed when delivered again.
+    pub fn forget(&mut self, id: &str) {
+        if self.replies.remove(id).is_some() {
+            self.order.retain(|other| other != id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply() -> CachedReply {
+        CachedReply {
+            cmd: GVMCmd::GetStatus,
+            resp: Some("ok".to_owned()),
+            data: None,
+            finished: true,
+        }
+    }
+
+    #[test]
+    fn resends_finished_reply() {
+        let mut replay = ReplayCache::new();
+        assert!(matches!(replay.deliver("a"), Delivery::New));
+        assert!(matches!(replay.deliver("a"), Delivery::Running));
+
+        replay.finish("a", reply());
+        match replay.deliver("a") {
+            Delivery::Done(reply) => assert_eq!(reply.resp.as_deref(), Some("ok")),
+            delivery => panic!("expected the cached reply, got {:?}", delivery),
+        }
+    }
+
+    #[test]
+    fn runs_forgotten_command_again() {
+        let mut replay = ReplayCache::new();
+        assert!(matches!(replay.deliver("a"), Delivery::New));
+
+        // Rejected out of order, then resent in order.
+        replay.forget("a");
+        assert!(matches!(replay.deliver("a"), Delivery::New));
+    }
+}
@@ -0,0 +1,100 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This runs the main loop of the guest program on tokio, in builds with the `async` feature.
+//!
+//! The host communication device and the plugins only have blocking APIs, so reading the
+//! next message and handling it both run on the blocking thread pool of the runtime. SIGHUP
+//! and the health file are handled on the runtime itself as soon as they are due, so unlike
+//! the synchronous main loop neither waits for the host nor needs comms_timeout set.
+//!
+//! Commands are still handled one at a time, in the order the host sent them.
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Builder;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task;
+use tokio::time;
+
+use crate::common::GVMError;
+use crate::status::CommsState;
+use crate::{read_message, reconnect_comms, Agent, Flow};
+
+/// Interval at which the health file is rewritten while waiting for the host.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Answers the commands of the host one after the other, until the host shuts the guest
+/// down. The configuration is read again as soon as SIGHUP sets `reload`.
+pub fn main_loop(agent: Agent, reload: Arc<AtomicBool>) -> Result<(), GVMError> {
+    let runtime = Builder::new_current_thread().enable_all().build()?;
+    let res = runtime.block_on(run(agent, reload));
+    // The pending read only returns once the host sends something, it isn't waited for.
+    runtime.shutdown_background();
+    res
+}
+
+async fn run(mut agent: Agent, reload: Arc<AtomicBool>) -> Result<(), GVMError> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut health = time::interval(HEALTH_INTERVAL);
+    let mut read = task::spawn_blocking(read_message);
+    // Times in a row the line to the host was reopened without receiving anything.
+    let mut reconnects = 0;
+
+    loop {
+        // Also catches SIGHUP received before the runtime started listening for it.
+        if reload.swap(false, Ordering::Relaxed) {
+            agent.reload();
+        }
+
+        tokio::select! {
+            _ = hangup.recv() => continue,
+            _ = health.tick() => {
+                agent.write_health(CommsState::Open);
+                continue;
+            }
+            message = &mut read => {
+                let message = match message.map_err(io::Error::from)? {
+                    Ok(message) => message,
+                    // A reopened line which closes again right away counts as another
+                    // attempt.
+                    Err(GVMError::CommsClosed)
+                        if reconnects < agent.config.comms_reconnect_attempts =>
+                    {
+                        agent.write_health(CommsState::Reconnecting);
+                        reconnects += 1;
+                        let config = agent.config.clone();
+                        task::spawn_blocking(move || reconnect_comms(&config, reconnects))
+                            .await
+                            .map_err(io::Error::from)?;
+                        read = task::spawn_blocking(read_message);
+                        continue;
+                    }
+                    Err(err) => {
+                        agent.write_health(CommsState::Closed);
+                        return Err(err);
+                    }
+                };
+                if !message.is_empty() {
+                    reconnects = 0;
+                }
+
+                // Commands can block for a long time, ie. plugin calls or waiting on links,
+                // so the agent moves to the blocking thread pool while handling them.
+                let (handled, flow) = task::spawn_blocking(move || {
+                    let flow = agent.handle(&message);
+                    (agent, flow)
+                })
+                .await
+                .map_err(io::Error::from)?;
+                agent = handled;
+                if flow? == Flow::Shutdown {
+                    return Ok(());
+                }
+
+                agent.write_health(CommsState::Open);
+                read = task::spawn_blocking(read_message);
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+// SPDX-FileCopyrightText: Copyright (c) 2666680 Ontario Inc. All rights reserved.
+// SPDX-License-Identifier: GPL-2.0
+//! This keeps track of the state of the GVM guest program for health reporting.
+//!
+//! Besides the [StatusReport] sent over comms, a [Health] file is kept up to date, so a
+//! watchdog on the host can detect a hung guest program even when comms are stuck.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::common::{GVMCmd, Network, PluginLimits};
+use crate::files::write_atomic;
+
+/// Outcome of the network initialization of the guest.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetInit {
+    /// Networking was already initialized by a previous run.
+    AlreadyInitialized,
+    /// Networking was initialized by this run.
+    Initialized,
+    /// Networking initialization failed.
+    Failed,
+    /// The network configuration was removed by a reset, it is initialized again on the next
+    /// start.
+    Reset,
+}
+
+/// Contents of the marker file written once networking has been initialized.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NetMarker {
+    /// Unix time networking was initialized at.
+    pub initialized_at: u64,
+    /// MAC addresses of the NICs configured, bond members included.
+    pub macs: Vec<String>,
+}
+
+impl NetMarker {
+    /// Marks the networks `nets` as initialized now.
+    pub fn new(nets: &[Network]) -> NetMarker {
+        let mut macs: Vec<String> = Vec::new();
+        for net in nets {
+            if !net.mac.is_empty() {
+                macs.push(net.mac.to_string());
+            }
+            if let Some(bond) = &net.bond {
+                macs.extend(bond.members.iter().map(|mac| mac.to_lowercase()));
+            }
+        }
+
+        NetMarker {
+            initialized_at: unix_secs(SystemTime::now()),
+            macs,
+        }
+    }
+
+    /// Reads the marker file at `path`, `None` if it is missing or was written by a version
+    /// only leaving a plain text marker.
+    pub fn read(path: &Path) -> Option<NetMarker> {
+        let contents = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+}
+
+/// Network initialization state reported to the host.
+#[derive(Serialize, Debug)]
+pub struct NetInitReport {
+    /// Whether networking was initialized, by this run or a previous one.
+    pub initialized: bool,
+    /// Outcome of the network initialization of this run.
+    pub net_init: NetInit,
+    /// Unix time networking was initialized at, unknown for old markers.
+    pub initialized_at: Option<u64>,
+    /// MAC addresses of the NICs configured, empty for old markers.
+    pub macs: Vec<String>,
+}
+
+/// State of the line to the host.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommsState {
+    /// Messages are being exchanged with the host.
+    Open,
+    /// The line to the host closed, and is being reopened.
+    Reconnecting,
+    /// The line to the host failed, the guest program is exiting.
+    Closed,
+}
+
+/// Liveness of the guest program, written to the health file.
+#[derive(Serialize, Debug)]
+pub struct Health {
+    /// Unix time the file was written at.
+    pub updated: u64,
+    /// Unix time of the last message received from the host.
+    pub last_activity: u64,
+    /// State of the line to the host.
+    pub comms: CommsState,
+    /// Number of plugins currently loaded.
+    pub plugins_loaded: usize,
+    /// Whether the host paused command execution.
+    pub paused: bool,
+}
+
+/// Time spent processing one type of command.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CommandMetrics {
+    /// Number of commands processed.
+    pub count: u64,
+    /// Number of commands which failed.
+    pub errors: u64,
+    /// Milliseconds spent processing all of the commands.
+    pub total_ms: u64,
+    /// Milliseconds spent processing the slowest command.
+    pub max_ms: u64,
+}
+
+/// Counters maintained by the main loop.
+pub struct Status {
+    /// When the guest program started.
+    started: Instant,
+    /// Number of commands processed.
+    pub commands: u64,
+    /// Last error returned to the host.
+    pub last_error: Option<String>,
+    /// Outcome of the network initialization.
+    pub net_init: NetInit,
+    /// Whether the host paused command execution.
+    pub paused: bool,
+    /// Processing time of the commands, keyed by command.
+    metrics: BTreeMap<String, CommandMetrics>,
+    /// When the last message was received from the host.
+    last_activity: SystemTime,
+    /// Whether the last write of the health file failed, so failures are only logged once.
+    health_failed: bool,
+}
+
+/// Snapshot of [Status] sent to the host.
+#[derive(Serialize, Debug)]
+pub struct StatusReport {
+    /// Seconds since the guest program started.
+    pub uptime: u64,
+    /// Number of commands processed.
+    pub commands: u64,
+    /// Number of plugins currently loaded.
+    pub plugins_loaded: usize,
+    /// Limits applied when starting the loaded plugins, keyed by path.
+    pub plugin_limits: BTreeMap<String, PluginLimits>,
+    /// Last error returned to the host.
+    pub last_error: Option<String>,
+    /// Outcome of the network initialization.
+    pub net_init: NetInit,
+    /// Whether the host paused command execution.
+    pub paused: bool,
+    /// Processing time of the commands, keyed by command. Network initialization is
+    /// recorded under `GetNetwork`.
+    pub metrics: BTreeMap<String, CommandMetrics>,
+}
+
+impl Status {
+    /// Starts tracking the status of the guest program.
+    pub fn new(net_init: NetInit) -> Status {
+        Status {
+            started: Instant::now(),
+            commands: 0,
+            last_error: None,
+            net_init,
+            paused: false,
+            metrics: BTreeMap::new(),
+            last_activity: SystemTime::now(),
+            health_failed: false,
+        }
+    }
+
+    /// Records a `cmd` which took `elapsed` to process, and whether it `failed`.
+    pub fn record(&mut self, cmd: GVMCmd, elapsed: Duration, failed: bool) {
+        let ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        let metrics = self.metrics.entry(format!("{:?}", cmd)).or_default();
+        metrics.count += 1;
+        metrics.errors += u64::from(failed);
+        metrics.total_ms = metrics.total_ms.saturating_add(ms);
+        metrics.max_ms = metrics.max_ms.max(ms);
+    }
+
+    /// Creates a report of the network initialization, as recorded by the marker file at
+    /// `marker_path`.
+    pub fn net_init_report(&self, marker_path: &Path) -> NetInitReport {
+        let marker = NetMarker::read(marker_path);
+        NetInitReport {
+            initialized: marker_path.exists(),
+            net_init: self.net_init,
+            initialized_at: marker.as_ref().map(|marker| marker.initialized_at),
+            macs: marker.map(|marker| marker.macs).unwrap_or_default(),
+        }
+    }
+
+    /// Records a message received from the host.
+    pub fn touch(&mut self) {
+        self.last_activity = SystemTime::now();
+    }
+
+    /// Creates a report of the current status, with `plugins_loaded` plugins started with
+    /// `plugin_limits`.
+    pub fn report(
+        &self,
+        plugins_loaded: usize,
+        plugin_limits: BTreeMap<String, PluginLimits>,
+    ) -> StatusReport {
+        StatusReport {
+            uptime: self.started.elapsed().as_secs(),
+            commands: self.commands,
+            plugins_loaded,
+            plugin_limits,
+            last_error: self.last_error.clone(),
+            net_init: self.net_init,
+            paused: self.paused,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Writes the health of the guest program to `path`, with `plugins_loaded` plugins and
+    /// comms in the `comms` state. Failures are logged when they start and stop, but don't
+    /// stop the guest program.
+    pub fn write_health(&mut self, path: &Path, plugins_loaded: usize, comms: CommsState) {
+        let health = Health {
+            updated: unix_secs(SystemTime::now()),
+            last_activity: unix_secs(self.last_activity),
+            comms,
+            plugins_loaded,
+            paused: self.paused,
+        };
+
+        let contents = serde_json::to_string(&health).unwrap();
+        let res = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| write_atomic(path, &contents, 0o644));
+        match res {
+            Ok(()) if self.health_failed => {
+                info!("Health file {} written again", path.display());
+                self.health_failed = false;
+            }
+            Err(err) if !self.health_failed => {
+                warn!("Failed to write health file {}: {:?}", path.display(), err);
+                self.health_failed = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Seconds from the unix epoch to `time`.
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}